@@ -0,0 +1,335 @@
+//! Running a [`Conversation`](crate::Conversation) template over many inputs.
+//!
+//! Inputs are read from a JSONL file where each line is an object with an
+//! `input` string and an optional `id` (which defaults to the line number):
+//!
+//! ```json
+//! {"id": "malcolm", "input": "Malcolm X"}
+//! ```
+//!
+//! Results are appended to a JSONL file as they complete,
+//! each line holding either an `output` or an `error` for its `id`.
+//! Items whose `id` already has an `output` there are skipped,
+//! so an interrupted batch can be resumed by running it again.
+
+use std::collections::HashSet;
+use std::fs::File;
+use std::fs::OpenOptions;
+use std::io::BufRead;
+use std::io::BufReader;
+use std::io::Read;
+use std::io::Seek;
+use std::io::SeekFrom;
+use std::io::Write;
+use std::io::{self};
+use std::num::NonZeroU32;
+use std::num::NonZeroUsize;
+use std::path::Path;
+use std::path::PathBuf;
+use std::time::Duration;
+use std::time::SystemTime;
+
+use clap::Args;
+use futures::StreamExt;
+use indicatif::ProgressBar;
+use indicatif::ProgressStyle;
+use serde::Deserialize;
+use serde::Serialize;
+use serde_json::json;
+use serde_json::Value;
+use tokio::sync::Mutex;
+use tokio::time::Interval;
+use tokio::time::MissedTickBehavior;
+
+use crate::history::Invocation;
+use crate::parse_conversation;
+use crate::Bot;
+use crate::CliError;
+use crate::Message;
+
+/// Run a conversation over every input of a JSONL file.
+#[derive(Debug, Args)]
+pub struct BatchCommand {
+    /// Path to a conversation YAML file,
+    /// or `@name` of a prompt in the library.
+    conversation: String,
+
+    /// Path to a JSONL file of inputs.
+    #[arg(long)]
+    input: PathBuf,
+
+    /// Path to a JSONL file where results are appended.
+    ///
+    /// Inputs that already have an output there are skipped.
+    #[arg(long)]
+    output: PathBuf,
+
+    /// Maximum number of requests in flight.
+    #[arg(long, default_value = "4")]
+    concurrency: NonZeroUsize,
+
+    /// Maximum number of requests started per minute.
+    #[arg(long)]
+    rate: Option<NonZeroU32>,
+}
+
+/// An input of a batch.
+#[derive(Debug, Deserialize)]
+struct Item {
+    /// Identifier of the [`Item`], used for resuming.
+    #[serde(default)]
+    id: Value,
+    /// The user message.
+    input: String,
+}
+
+/// A result of a batch.
+#[derive(Debug, Serialize, Deserialize)]
+struct Record {
+    /// Identifier of the corresponding [`Item`].
+    id: Value,
+    /// The assistant message, if the request succeeded.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    output: Option<String>,
+    /// The error message, if the request failed.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+/// A limiter that spaces out the start of requests.
+#[derive(Debug)]
+struct RateLimiter(Option<Mutex<Interval>>);
+
+impl RateLimiter {
+    /// Create a [`RateLimiter`] allowing `rate` requests per minute, if any.
+    #[inline]
+    fn new(rate: Option<NonZeroU32>) -> Self {
+        Self(rate.map(|rate| {
+            let mut interval = tokio::time::interval(Duration::from_secs(60) / rate.get());
+            interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+            Mutex::new(interval)
+        }))
+    }
+
+    /// Wait until the next request is allowed to start.
+    #[inline]
+    async fn wait(&self) {
+        if let Some(interval) = &self.0 {
+            interval.lock().await.tick().await;
+        }
+    }
+}
+
+impl BatchCommand {
+    /// Run this [`BatchCommand`].
+    #[inline]
+    pub async fn run(self, bot: &Bot) -> Result<(), CliError> {
+        let template = parse_conversation(&self.conversation)?;
+        let done = completed(&self.output)?;
+        let items: Vec<_> = read_items(&self.input)?
+            .into_iter()
+            .filter(|item| !done.contains(&key(&item.id)))
+            .collect();
+        log::debug!(
+            "skipping {done} completed items, running {pending}",
+            done = done.len(),
+            pending = items.len()
+        );
+
+        let mut output = open_results(&self.output)?;
+
+        let progress = ProgressBar::new(items.len() as u64).with_style(
+            ProgressStyle::with_template(
+                "{bar:40} {pos}/{len} [{elapsed_precise}<{eta_precise}] {msg}",
+            )
+            .expect("progress template should be valid"),
+        );
+
+        let limiter = RateLimiter::new(self.rate);
+        let mut records = futures::stream::iter(items)
+            .map(|item| {
+                let mut conversation = template.clone();
+                conversation.push(Message::from_user(item.input));
+
+                let limiter = &limiter;
+                let path = template.path.clone();
+                async move {
+                    limiter.wait().await;
+
+                    let started = SystemTime::now();
+                    match bot.reply(&conversation, Vec::new()).await {
+                        Ok(reply) => {
+                            bot.log(&Invocation {
+                                started,
+                                duration: started.elapsed().unwrap_or_default(),
+                                path,
+                                conversation: &conversation,
+                                parameters: json!({
+                                    "model": bot.model(&conversation),
+                                    "max_tokens": bot.max_tokens,
+                                    "batch": item.id,
+                                }),
+                                reply: &reply.text,
+                                usage: reply.usage,
+                            });
+                            Record {
+                                id: item.id,
+                                output: Some(reply.text),
+                                error: None,
+                            }
+                        }
+                        Err(err) => Record {
+                            id: item.id,
+                            output: None,
+                            error: Some(err.to_string()),
+                        },
+                    }
+                }
+            })
+            .buffer_unordered(self.concurrency.get());
+
+        let mut failures = 0_usize;
+        while let Some(record) = records.next().await {
+            if record.error.is_some() {
+                failures += 1;
+                progress.set_message(format!("{failures} failed"));
+            }
+
+            serde_json::to_writer(&mut output, &record)?;
+            writeln!(output)?;
+            output.flush()?;
+            progress.inc(1);
+        }
+        progress.finish();
+
+        Ok(())
+    }
+}
+
+/// Read the [`Item`]s of a JSONL file.
+#[inline]
+fn read_items(path: &Path) -> Result<Vec<Item>, CliError> {
+    let mut items = Vec::new();
+    for (index, line) in BufReader::new(File::open(path)?).lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let mut item: Item = serde_json::from_str(&line).map_err(|source| CliError::Jsonl {
+            line: index + 1,
+            source,
+        })?;
+        if item.id.is_null() {
+            item.id = Value::from(index + 1);
+        }
+        items.push(item);
+    }
+    Ok(items)
+}
+
+/// Open a JSONL file of results for appending.
+///
+/// A line cut off by an interruption is terminated first,
+/// so that it does not corrupt the next record.
+#[inline]
+fn open_results(path: &Path) -> io::Result<File> {
+    let mut file = OpenOptions::new()
+        .create(true)
+        .read(true)
+        .append(true)
+        .open(path)?;
+    if file.metadata()?.len() > 0 {
+        let mut last = [0_u8];
+        file.seek(SeekFrom::End(-1))?;
+        file.read_exact(&mut last)?;
+        if last[0] != b'\n' {
+            writeln!(file)?;
+        }
+    }
+    Ok(file)
+}
+
+/// Collect the keys of the successful [`Record`]s of a JSONL file.
+///
+/// A missing file has no records,
+/// and lines that cannot be parsed (e.g., truncated by an interruption) are
+/// ignored.
+#[inline]
+fn completed(path: &Path) -> Result<HashSet<String>, CliError> {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(HashSet::new()),
+        Err(err) => return Err(err.into()),
+    };
+
+    let mut done = HashSet::new();
+    for line in BufReader::new(file).lines() {
+        match serde_json::from_str::<Record>(&line?) {
+            Ok(record) if record.output.is_some() => {
+                done.insert(key(&record.id));
+            }
+            Ok(_) => {}
+            Err(err) => log::warn!("ignoring malformed result: {err}"),
+        }
+    }
+    Ok(done)
+}
+
+/// Get a comparable key for an identifier.
+///
+/// Strings and numbers with the same textual representation are considered
+/// the same identifier.
+#[inline]
+fn key(id: &Value) -> String {
+    match id {
+        Value::String(id) => id.clone(),
+        id => id.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keys_ignore_identifier_types() {
+        assert_eq!(key(&Value::from("42")), key(&Value::from(42)));
+        assert_ne!(key(&Value::from("a")), key(&Value::from("b")));
+    }
+
+    #[test]
+    fn interrupted_batches_resume() {
+        let directory = std::env::temp_dir().join(format!("answer-batch-{}", std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+
+        let input = directory.join("input.jsonl");
+        std::fs::write(
+            &input,
+            "{\"id\": \"a\", \"input\": \"A\"}\n\n{\"input\": \"B\"}\n{\"input\": \"C\"}\n",
+        )
+        .unwrap();
+        let items = read_items(&input).unwrap();
+        let ids: Vec<_> = items.iter().map(|item| key(&item.id)).collect();
+        assert_eq!(ids, ["a", "3", "4"]);
+        assert_eq!(items[1].input, "B");
+
+        let output = directory.join("output.jsonl");
+        std::fs::write(
+            &output,
+            "{\"id\":\"a\",\"output\":\"done\"}\n{\"id\":3,\"error\":\"failed\"}\n{\"id\":4,\"outp",
+        )
+        .unwrap();
+        assert_eq!(completed(&output).unwrap(), HashSet::from(["a".to_owned()]));
+
+        let mut file = open_results(&output).unwrap();
+        writeln!(file, "{{\"id\":4,\"output\":\"done\"}}").unwrap();
+        drop(file);
+        assert_eq!(
+            completed(&output).unwrap(),
+            HashSet::from(["a".to_owned(), "4".to_owned()])
+        );
+
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+}
@@ -0,0 +1,729 @@
+//! Spend limits.
+//!
+//! Every request is recorded in a local ledger,
+//! along with its estimated cost,
+//! under a profile (`default` unless `--profile` is given),
+//! whichever command made it.
+//! Daily and monthly budgets per profile are set in `answer/budget.yml`
+//! in the user configuration directory:
+//!
+//! ```yaml
+//! profiles:
+//!   default:
+//!     daily: 0.50 # dollars
+//!     monthly: 5
+//!   work:
+//!     monthly: 50
+//!     max_tokens: 2048 # longest reply, 1024 by default
+//! prices: # dollars per million tokens, for models not known already
+//!   my-fine-tuned-model: { input: 3, output: 12 }
+//! ```
+//!
+//! Before every request,
+//! the maximum cost of the request is estimated from the length of the
+//! conversation and the longest reply allowed.
+//! If it would exceed a budget,
+//! `answer` asks for confirmation on a terminal,
+//! and refuses otherwise.
+//! Commands making requests concurrently, such as `batch`, `compare`,
+//! `serve`, `mcp-serve` and prompt chains, always refuse.
+//! The estimate is reserved in the ledger until the request is answered,
+//! so that concurrent requests cannot all spend the same budget.
+//! Token counts are estimated from lengths,
+//! unless reported by the API,
+//! so costs are approximate.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs::File;
+use std::fs::{self};
+use std::io::IsTerminal;
+use std::io::{self};
+use std::path::PathBuf;
+use std::time::Duration;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+
+use async_openai::types::Usage;
+use clap::Args;
+use rusqlite::params;
+use rusqlite::Connection;
+use rustyline::error::ReadlineError;
+use rustyline::DefaultEditor;
+use serde::Deserialize;
+
+use crate::Bot;
+use crate::BotError;
+use crate::CliError;
+use crate::Conversation;
+
+/// Name of the profile used when none is given.
+pub const DEFAULT_PROFILE: &str = "default";
+
+/// Longest reply allowed under a budget when none is given.
+const DEFAULT_MAX_TOKENS: u16 = 1024;
+
+/// Tokens added for every message.
+const TOKENS_PER_MESSAGE: usize = 4;
+
+/// Prices of known models,
+/// in dollars per million input and output tokens.
+const PRICES: &[(&str, Price)] = &[
+    (
+        "gpt-3.5-turbo",
+        Price {
+            input: 0.5,
+            output: 1.5,
+        },
+    ),
+    (
+        "gpt-4o-mini",
+        Price {
+            input: 0.15,
+            output: 0.6,
+        },
+    ),
+    (
+        "gpt-4o",
+        Price {
+            input: 2.5,
+            output: 10.0,
+        },
+    ),
+    (
+        "gpt-4-turbo",
+        Price {
+            input: 10.0,
+            output: 30.0,
+        },
+    ),
+    (
+        "gpt-4",
+        Price {
+            input: 30.0,
+            output: 60.0,
+        },
+    ),
+    (
+        "text-embedding-ada-002",
+        Price {
+            input: 0.1,
+            output: 0.0,
+        },
+    ),
+    // Speech costs $15 per million characters,
+    // or about four times as much per token.
+    (
+        "tts-1",
+        Price {
+            input: 60.0,
+            output: 0.0,
+        },
+    ),
+];
+
+/// Schema of the ledger database.
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS spending (
+    id INTEGER PRIMARY KEY,
+    profile TEXT NOT NULL,
+    spent_at INTEGER NOT NULL,
+    model TEXT NOT NULL,
+    prompt_tokens INTEGER NOT NULL,
+    completion_tokens INTEGER NOT NULL,
+    cost REAL NOT NULL
+);
+";
+
+/// How long to wait for other processes checking the same ledger.
+const BUSY_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// The price of a model,
+/// in dollars per million tokens.
+#[derive(Clone, Copy, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Price {
+    /// Price of input tokens.
+    input: f64,
+    /// Price of output tokens.
+    output: f64,
+}
+
+impl Price {
+    /// Get the cost of a request.
+    #[inline]
+    fn cost(self, prompt_tokens: usize, completion_tokens: usize) -> f64 {
+        let (prompt_tokens, completion_tokens) = (prompt_tokens as f64, completion_tokens as f64);
+        prompt_tokens.mul_add(self.input, completion_tokens * self.output) / 1_000_000.0
+    }
+}
+
+/// Budgets of a profile,
+/// in dollars.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Limits {
+    /// Budget per day.
+    pub daily: Option<f64>,
+    /// Budget per month.
+    pub monthly: Option<f64>,
+    /// Longest reply allowed,
+    /// in tokens.
+    pub max_tokens: Option<u16>,
+}
+
+impl Limits {
+    /// Load the [`Limits`] of a profile from the budget configuration,
+    /// if any.
+    #[inline]
+    pub fn of(profile: &str) -> Result<Option<Self>, CliError> {
+        Ok(Config::load()?.profiles.remove(profile))
+    }
+}
+
+/// The budget configuration file.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Config {
+    /// [`Limits`] per profile.
+    #[serde(default)]
+    profiles: BTreeMap<String, Limits>,
+    /// [`Price`]s of models not known already.
+    #[serde(default)]
+    prices: BTreeMap<String, Price>,
+}
+
+impl Config {
+    /// Load the [`Config`] from the user configuration directory,
+    /// if any.
+    #[inline]
+    fn load() -> Result<Self, CliError> {
+        let path = dirs::config_dir()
+            .ok_or(CliError::NoConfigDir)?
+            .join("answer")
+            .join("budget.yml");
+        match File::open(path) {
+            Ok(file) => Ok(serde_yaml::from_reader(file)?),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Get the [`Price`] of a model,
+    /// if known.
+    #[inline]
+    fn price(&self, model: &str) -> Option<Price> {
+        self.prices.get(model).copied().or_else(|| {
+            // Dated snapshots cost the same as their model.
+            PRICES
+                .iter()
+                .find(|(known, _)| model == *known || model.starts_with(&format!("{known}-")))
+                .map(|&(_, price)| price)
+        })
+    }
+}
+
+/// A period over which spending is limited.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Period {
+    /// The current day.
+    Daily,
+    /// The current month.
+    Monthly,
+}
+
+impl Period {
+    /// Get the SQLite format of dates in the same [`Period`].
+    #[inline]
+    const fn format(self) -> &'static str {
+        match self {
+            Self::Daily => "%Y-%m-%d",
+            Self::Monthly => "%Y-%m",
+        }
+    }
+}
+
+impl fmt::Display for Period {
+    #[inline]
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Daily => write!(f, "daily"),
+            Self::Monthly => write!(f, "monthly"),
+        }
+    }
+}
+
+/// The ledger of spending.
+#[derive(Debug)]
+struct Ledger {
+    /// Connection to the database.
+    connection: Connection,
+}
+
+impl Ledger {
+    /// Open the [`Ledger`] in the user data directory,
+    /// creating it as needed.
+    #[inline]
+    fn open() -> Result<Self, CliError> {
+        let path = path().ok_or(CliError::NoDataDir)?;
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        Self::with_connection(Connection::open(path)?)
+    }
+
+    /// Create a [`Ledger`] over a [`Connection`],
+    /// creating tables as needed.
+    #[inline]
+    fn with_connection(connection: Connection) -> Result<Self, CliError> {
+        connection.busy_timeout(BUSY_TIMEOUT)?;
+        connection.execute_batch(SCHEMA)?;
+        Ok(Self { connection })
+    }
+
+    /// Run a closure in a transaction that takes the write lock at once,
+    /// so that concurrent checks of the same ledger wait for each other.
+    #[inline]
+    fn exclusively<T>(&self, f: impl FnOnce(&Self) -> Result<T, CliError>) -> Result<T, CliError> {
+        self.connection.execute_batch("BEGIN IMMEDIATE")?;
+        let result = f(self);
+        self.connection
+            .execute_batch(if result.is_ok() { "COMMIT" } else { "ROLLBACK" })?;
+        result
+    }
+
+    /// Record spending for a profile.
+    ///
+    /// Returns the id of the entry, to [settle](Self::settle) it later.
+    #[inline]
+    fn record(
+        &self,
+        profile: &str,
+        model: &str,
+        prompt_tokens: usize,
+        completion_tokens: usize,
+        cost: f64,
+    ) -> Result<i64, CliError> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |since| {
+                i64::try_from(since.as_secs()).unwrap_or(i64::MAX)
+            });
+        self.connection.execute(
+            "INSERT INTO spending \
+             (profile, spent_at, model, prompt_tokens, completion_tokens, cost) \
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            params![
+                profile,
+                now,
+                model,
+                i64::try_from(prompt_tokens).unwrap_or(i64::MAX),
+                i64::try_from(completion_tokens).unwrap_or(i64::MAX),
+                cost
+            ],
+        )?;
+        Ok(self.connection.last_insert_rowid())
+    }
+
+    /// Replace the estimated spending of an entry with the actual one.
+    #[inline]
+    fn settle(
+        &self,
+        id: i64,
+        prompt_tokens: usize,
+        completion_tokens: usize,
+        cost: f64,
+    ) -> Result<(), CliError> {
+        self.connection.execute(
+            "UPDATE spending SET prompt_tokens = ?2, completion_tokens = ?3, cost = ?4 \
+             WHERE id = ?1",
+            params![
+                id,
+                i64::try_from(prompt_tokens).unwrap_or(i64::MAX),
+                i64::try_from(completion_tokens).unwrap_or(i64::MAX),
+                cost
+            ],
+        )?;
+        Ok(())
+    }
+
+    /// Remove an entry, for a request that was never answered.
+    #[inline]
+    fn cancel(&self, id: i64) -> Result<(), CliError> {
+        self.connection
+            .execute("DELETE FROM spending WHERE id = ?1", params![id])?;
+        Ok(())
+    }
+
+    /// Get the spending of a profile over the current [`Period`].
+    #[inline]
+    fn spent(&self, profile: &str, period: Period) -> Result<f64, CliError> {
+        Ok(self.connection.query_row(
+            "SELECT COALESCE(SUM(cost), 0) FROM spending WHERE profile = ?1 \
+             AND strftime(?2, spent_at, 'unixepoch', 'localtime') \
+             = strftime(?2, 'now', 'localtime')",
+            params![profile, period.format()],
+            |row| row.get(0),
+        )?)
+    }
+
+    /// List the profiles with any spending.
+    #[inline]
+    fn profiles(&self) -> Result<Vec<String>, CliError> {
+        let profiles = self
+            .connection
+            .prepare("SELECT DISTINCT profile FROM spending ORDER BY profile")?
+            .query_map([], |row| row.get(0))?
+            .collect::<Result<_, _>>()?;
+        Ok(profiles)
+    }
+}
+
+/// Get the path of the ledger database.
+#[inline]
+fn path() -> Option<PathBuf> {
+    dirs::data_dir().map(|directory| directory.join("answer").join("budget.sqlite3"))
+}
+
+/// The budget of a profile,
+/// enforced before answering.
+#[derive(Debug)]
+pub struct Budget {
+    /// Name of the profile.
+    profile: String,
+    /// [`Limits`] of the profile, if any.
+    limits: Option<Limits>,
+    /// The budget configuration.
+    config: Config,
+    /// The ledger of spending.
+    ledger: Ledger,
+    /// Whether there is a terminal to ask for confirmation on.
+    interactive: bool,
+}
+
+impl Budget {
+    /// Open the [`Budget`] of a profile,
+    /// asking for confirmation on a terminal only if `confirms`.
+    #[inline]
+    pub fn open(profile: &str, confirms: bool) -> Result<Self, CliError> {
+        let config = Config::load()?;
+        Ok(Self {
+            profile: profile.to_owned(),
+            limits: config.profiles.get(profile).cloned(),
+            config,
+            ledger: Ledger::open()?,
+            interactive: confirms && io::stdin().is_terminal() && io::stderr().is_terminal(),
+        })
+    }
+
+    /// Check that a request fits in the budget,
+    /// asking for confirmation on a terminal if it does not,
+    /// and reserve its estimated cost.
+    ///
+    /// Returns the longest reply to allow,
+    /// so that the estimate is an upper bound,
+    /// and the id of the reservation to [settle](Self::settle).
+    #[inline]
+    pub fn check(
+        &self,
+        model: &str,
+        prompt_tokens: usize,
+        max_tokens: Option<u16>,
+    ) -> Result<(Option<u16>, i64), CliError> {
+        // Checking and reserving in one transaction keeps concurrent
+        // requests from all passing the same check.
+        self.ledger.exclusively(|ledger| {
+            let max_tokens = self.allow(ledger, model, prompt_tokens, max_tokens)?;
+            let completion_tokens = max_tokens.map_or(0, usize::from);
+            let estimate = self
+                .config
+                .price(model)
+                .map_or(0.0, |price| price.cost(prompt_tokens, completion_tokens));
+            let id = ledger.record(
+                &self.profile,
+                model,
+                prompt_tokens,
+                completion_tokens,
+                estimate,
+            )?;
+            Ok((max_tokens, id))
+        })
+    }
+
+    /// Check that a request fits in the budget against a ledger,
+    /// asking for confirmation on a terminal if it does not.
+    ///
+    /// Returns the longest reply to allow.
+    #[inline]
+    fn allow(
+        &self,
+        ledger: &Ledger,
+        model: &str,
+        prompt_tokens: usize,
+        max_tokens: Option<u16>,
+    ) -> Result<Option<u16>, CliError> {
+        let Some(limits) = &self.limits else {
+            return Ok(max_tokens);
+        };
+        let max_tokens = max_tokens
+            .or(limits.max_tokens)
+            .unwrap_or(DEFAULT_MAX_TOKENS);
+        let Some(price) = self.config.price(model) else {
+            log::warn!("no price known for model {model:?}, so the budget is not enforced");
+            return Ok(Some(max_tokens));
+        };
+
+        let estimate = price.cost(prompt_tokens, max_tokens.into());
+        for (period, limit) in [
+            (Period::Daily, limits.daily),
+            (Period::Monthly, limits.monthly),
+        ] {
+            let Some(limit) = limit else {
+                continue;
+            };
+            let spent = ledger.spent(&self.profile, period)?;
+            if spent + estimate > limit {
+                let err = CliError::OverBudget {
+                    profile: self.profile.clone(),
+                    period,
+                    limit,
+                    spent,
+                    estimate,
+                };
+                if !self.interactive || !confirm(&err)? {
+                    return Err(err);
+                }
+            }
+        }
+        Ok(Some(max_tokens))
+    }
+
+    /// Replace the reserved cost of a request with its actual cost.
+    #[inline]
+    pub fn settle(
+        &self,
+        id: i64,
+        model: &str,
+        prompt_tokens: usize,
+        completion_tokens: usize,
+    ) -> Result<(), CliError> {
+        let cost = self
+            .config
+            .price(model)
+            .map_or(0.0, |price| price.cost(prompt_tokens, completion_tokens));
+        self.ledger
+            .settle(id, prompt_tokens, completion_tokens, cost)
+    }
+}
+
+/// Spending reserved for a request until it is answered.
+///
+/// The reservation is released if dropped without being
+/// [settled](Self::settle), such as when the request fails.
+#[derive(Debug)]
+#[must_use]
+pub struct Reservation {
+    /// Profile and ledger entry of the reservation, if any.
+    entry: Option<(String, i64)>,
+    /// Model the request is sent to.
+    model: String,
+    /// Estimated tokens in the prompt.
+    prompt_tokens: usize,
+}
+
+impl Reservation {
+    /// Replace the reserved cost with the actual cost of the request.
+    ///
+    /// The token usage reported by the API is used if any,
+    /// and estimates otherwise.
+    /// Failures are only warned about,
+    /// as they should not lose the reply.
+    #[inline]
+    pub fn settle(mut self, reply: &str, usage: Option<&Usage>) {
+        let Some((profile, id)) = self.entry.take() else {
+            return;
+        };
+        let (prompt_tokens, completion_tokens) = match usage {
+            Some(usage) => (
+                usize::try_from(usage.prompt_tokens).unwrap_or(usize::MAX),
+                usize::try_from(usage.completion_tokens).unwrap_or(usize::MAX),
+            ),
+            None => (self.prompt_tokens, count_tokens(reply)),
+        };
+        if let Err(err) = Budget::open(&profile, false)
+            .and_then(|budget| budget.settle(id, &self.model, prompt_tokens, completion_tokens))
+        {
+            log::warn!("could not record spending: {err}");
+        }
+    }
+}
+
+impl Drop for Reservation {
+    #[inline]
+    fn drop(&mut self) {
+        if let Some((_, id)) = self.entry.take() {
+            if let Err(err) = Ledger::open().and_then(|ledger| ledger.cancel(id)) {
+                log::warn!("could not release reserved spending: {err}");
+            }
+        }
+    }
+}
+
+impl Bot {
+    /// Check that a request fits in the budget of the profile, if any,
+    /// asking for confirmation on a terminal if it does not and the
+    /// [`Bot`] [confirms](Bot::confirms), and reserve its estimated cost.
+    ///
+    /// Returns the longest reply to allow,
+    /// and the [`Reservation`] to settle once answered.
+    #[inline]
+    pub fn check_budget(
+        &self,
+        model: &str,
+        prompt_tokens: usize,
+        max_tokens: Option<u16>,
+    ) -> Result<(Option<u16>, Reservation), BotError> {
+        let mut reservation = Reservation {
+            entry: None,
+            model: model.to_owned(),
+            prompt_tokens,
+        };
+        let Some(profile) = &self.profile else {
+            return Ok((max_tokens, reservation));
+        };
+        let (max_tokens, id) = Budget::open(profile, self.confirms)
+            .and_then(|budget| budget.check(model, prompt_tokens, max_tokens))
+            .map_err(|err| BotError::Budget(Box::new(err)))?;
+        reservation.entry = Some((profile.clone(), id));
+        Ok((max_tokens, reservation))
+    }
+}
+
+/// Ask the user whether to go over budget.
+#[inline]
+fn confirm(err: &CliError) -> Result<bool, CliError> {
+    eprintln!("{err}");
+    let mut editor = DefaultEditor::new()?;
+    match editor.readline("Send anyway? [y/N] ") {
+        Ok(answer) => Ok(matches!(answer.trim().to_lowercase().as_str(), "y" | "yes")),
+        Err(ReadlineError::Interrupted | ReadlineError::Eof) => Ok(false),
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// Estimate the number of tokens in a text.
+#[inline]
+pub fn count_tokens(text: &str) -> usize {
+    // Tokens are about four characters long in English.
+    text.chars().count().div_ceil(4)
+}
+
+/// Estimate the number of tokens in a [`Conversation`].
+#[inline]
+pub fn count_conversation_tokens(conversation: &Conversation) -> usize {
+    conversation
+        .messages
+        .iter()
+        .map(|message| TOKENS_PER_MESSAGE + count_tokens(&message.content.to_string()))
+        .sum()
+}
+
+/// Show spending and budgets.
+#[derive(Debug, Args)]
+pub struct BudgetCommand {}
+
+impl BudgetCommand {
+    /// Run this [`BudgetCommand`],
+    /// showing only a profile if given.
+    #[inline]
+    pub fn run(self, profile: Option<&str>) -> Result<(), CliError> {
+        let config = Config::load()?;
+        let ledger = Ledger::open()?;
+
+        let profiles = match profile {
+            Some(profile) => vec![profile.to_owned()],
+            None => {
+                let mut profiles = ledger.profiles()?;
+                profiles.extend(config.profiles.keys().cloned());
+                profiles.sort();
+                profiles.dedup();
+                profiles
+            }
+        };
+        for profile in profiles {
+            let limits = config.profiles.get(&profile).cloned().unwrap_or_default();
+            println!(
+                "{profile}: today {daily}, this month {monthly}",
+                daily = usage(ledger.spent(&profile, Period::Daily)?, limits.daily),
+                monthly = usage(ledger.spent(&profile, Period::Monthly)?, limits.monthly),
+            );
+        }
+        Ok(())
+    }
+}
+
+/// Format spending against a budget.
+#[inline]
+fn usage(spent: f64, limit: Option<f64>) -> String {
+    match limit {
+        Some(limit) => format!("${spent:.4} of ${limit:.2}"),
+        None => format!("${spent:.4}"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Create a [`Budget`] of $0.01 a day over an in-memory [`Ledger`].
+    fn daily_budget() -> Budget {
+        Budget {
+            profile: DEFAULT_PROFILE.to_owned(),
+            limits: Some(Limits {
+                daily: Some(0.01),
+                monthly: None,
+                max_tokens: None,
+            }),
+            config: Config::default(),
+            ledger: Ledger::with_connection(Connection::open_in_memory().unwrap()).unwrap(),
+            interactive: false,
+        }
+    }
+
+    #[test]
+    fn requests_over_budget_are_refused() {
+        let budget = daily_budget();
+
+        // 1000 input and 1024 output tokens cost about $0.002.
+        let (max_tokens, id) = budget.check("gpt-3.5-turbo", 1000, None).unwrap();
+        assert_eq!(max_tokens, Some(DEFAULT_MAX_TOKENS));
+        budget
+            .settle(id, "gpt-3.5-turbo", 1000, count_tokens(&"a".repeat(20_000)))
+            .unwrap();
+        let spent = budget.ledger.spent(DEFAULT_PROFILE, Period::Daily).unwrap();
+        assert!((spent - 0.008).abs() < 1e-9);
+
+        assert!(matches!(
+            budget.check("gpt-3.5-turbo-0125", 1000, None),
+            Err(CliError::OverBudget {
+                period: Period::Daily,
+                ..
+            })
+        ));
+        assert_eq!(budget.check("unknown", 1000, Some(10)).unwrap().0, Some(10));
+    }
+
+    #[test]
+    fn pending_requests_are_reserved() {
+        let budget = daily_budget();
+
+        // Four pending requests reserve about $0.008.
+        let ids = (0..4)
+            .map(|_| budget.check("gpt-3.5-turbo", 1000, None).unwrap().1)
+            .collect::<Vec<_>>();
+        assert!(budget.check("gpt-3.5-turbo", 1000, None).is_err());
+
+        budget.ledger.cancel(ids[0]).unwrap();
+        assert!(budget.check("gpt-3.5-turbo", 1000, None).is_ok());
+    }
+}
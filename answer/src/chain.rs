@@ -0,0 +1,337 @@
+//! Chains of prompts.
+//!
+//! A conversation file with `steps` runs each step as its own conversation,
+//! feeding the outputs of earlier steps into the inputs of later ones:
+//!
+//! ```yaml
+//! steps:
+//!   - id: outline
+//!     conversation: outline.yml # relative to this file, or @name
+//!     input: '{{input}}' # the standard input
+//!   - id: draft
+//!     messages:
+//!       - role: system
+//!         content: You turn outlines into drafts.
+//!     input: '{{outline}}'
+//!   - id: title
+//!     conversation: '@titles'
+//!     input: '{{outline}}'
+//!   - id: article
+//!     messages:
+//!       - role: system
+//!         content: You format articles in Markdown.
+//!     input: "# {{title}}\n\n{{draft}}"
+//! ```
+//!
+//! Steps can only refer to earlier steps.
+//! Steps whose inputs are ready run concurrently
+//! (`draft` and `title` above),
+//! and the output of the last step is the answer.
+//! With `--moderate-reply`,
+//! every output is checked before it is used.
+//! Every output is logged in the history,
+//! and,
+//! with `--save`,
+//! also written to a directory named after the file
+//! (e.g., `article.steps/outline.txt`),
+//! even when a step running alongside fails.
+//!
+//! Chains do not support `--branch`, `--image`, `--index` or `--speak`.
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::OnceLock;
+use std::time::SystemTime;
+
+use futures::future;
+use regex::Captures;
+use regex::Regex;
+use serde::Deserialize;
+use serde::Serialize;
+use serde_json::json;
+
+use crate::history::Invocation;
+use crate::moderation::Moderation;
+use crate::parse_conversation;
+use crate::Bot;
+use crate::CliError;
+use crate::Conversation;
+use crate::Message;
+use crate::Reply;
+
+/// Name referring to the standard input in templates.
+const INPUT: &str = "input";
+
+/// A step of a chain.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Step {
+    /// Identifier of the step,
+    /// referred to by later steps as `{{id}}`.
+    id: String,
+    /// Path to a conversation YAML file relative to the chain file,
+    /// or `@name` of a prompt in the library.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    conversation: Option<String>,
+    /// [`Message`]s following the conversation, if any.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    messages: Vec<Message>,
+    /// Template of the user message,
+    /// or [`None`] to reply to the conversation as is.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    input: Option<String>,
+}
+
+/// A [`Step`] ready to run.
+#[derive(Debug)]
+struct Task<'a> {
+    /// The [`Step`].
+    step: &'a Step,
+    /// The [`Conversation`] of the [`Step`].
+    conversation: Conversation,
+    /// Identifiers of the [`Step`]s it depends on.
+    dependencies: Vec<String>,
+}
+
+/// Get the pattern of references in templates.
+#[inline]
+fn reference() -> &'static Regex {
+    static REFERENCE: OnceLock<Regex> = OnceLock::new();
+    REFERENCE.get_or_init(|| {
+        Regex::new(r"\{\{\s*([\w-]+)\s*\}\}").expect("the reference pattern should be valid")
+    })
+}
+
+/// Run the steps of a chain [`Conversation`] on an input,
+/// checking their outputs as moderation requires.
+///
+/// Returns the output of the last step.
+#[inline]
+pub async fn run(
+    bot: &Bot,
+    chain: &Conversation,
+    input: &str,
+    moderation: &Moderation,
+    save: bool,
+) -> Result<String, CliError> {
+    let tasks = plan(chain)?;
+    // Steps of a wave run at once, so none can ask for confirmation.
+    let bot = &Bot {
+        confirms: false,
+        ..bot.clone()
+    };
+    let mut outputs: HashMap<&str, String> = HashMap::new();
+    outputs.insert(INPUT, input.to_owned());
+
+    let directory = if save {
+        if chain.path.is_none() {
+            log::warn!("there is no chain file to save outputs next to");
+        }
+        chain.path.as_ref().map(|path| path.with_extension("steps"))
+    } else {
+        None
+    };
+    if let Some(directory) = &directory {
+        fs::create_dir_all(directory)?;
+    }
+
+    let mut pending: Vec<&Task<'_>> = tasks.iter().collect();
+    while !pending.is_empty() {
+        // References are to earlier steps only,
+        // so the first pending step is always ready.
+        let (ready, waiting): (Vec<_>, Vec<_>) = pending.into_iter().partition(|task| {
+            task.dependencies
+                .iter()
+                .all(|dependency| outputs.contains_key(dependency.as_str()))
+        });
+        pending = waiting;
+
+        let runs = ready.iter().map(|task| {
+            let mut conversation = task.conversation.clone();
+            if let Some(template) = &task.step.input {
+                conversation.push(Message::from_user(render(template, &outputs)));
+            }
+            async move {
+                let started = SystemTime::now();
+                log::info!("running step {id:?}", id = task.step.id);
+                let mut result = bot.reply(&conversation, Vec::new()).await;
+                if let Ok(reply) = &result {
+                    if moderation.checks_reply() {
+                        if let Err(err) = moderation.check(bot, &reply.text).await {
+                            result = Err(err);
+                        }
+                    }
+                }
+                (task, conversation, started, result)
+            }
+        });
+        // Every step of the wave is kept before failing,
+        // so that finished steps are not lost.
+        let mut failure = None;
+        for (task, conversation, started, result) in future::join_all(runs).await {
+            let id = task.step.id.as_str();
+            let Reply {
+                text: output,
+                usage,
+            } = match result {
+                Ok(reply) => reply,
+                Err(source) => {
+                    log::warn!("step {id:?} failed: {source}");
+                    failure.get_or_insert(CliError::StepFailed {
+                        step: id.to_owned(),
+                        source: Box::new(source),
+                    });
+                    continue;
+                }
+            };
+
+            bot.log(&Invocation {
+                started,
+                duration: started.elapsed().unwrap_or_default(),
+                path: chain.path.clone(),
+                conversation: &conversation,
+                parameters: json!({
+                    "model": bot.model(&conversation),
+                    "max_tokens": bot.max_tokens,
+                    "step": id,
+                }),
+                reply: &output,
+                usage,
+            });
+            if let Some(directory) = &directory {
+                fs::write(directory.join(format!("{id}.txt")), &output)?;
+            }
+            outputs.insert(id, output);
+        }
+        if let Some(err) = failure {
+            return Err(err);
+        }
+    }
+
+    let last = tasks.last().map(|task| task.step.id.as_str());
+    Ok(last.and_then(|id| outputs.remove(id)).unwrap_or_default())
+}
+
+/// Check the steps of a chain [`Conversation`] and load their
+/// [`Conversation`]s.
+#[inline]
+fn plan(chain: &Conversation) -> Result<Vec<Task<'_>>, CliError> {
+    let mut tasks: Vec<Task<'_>> = Vec::with_capacity(chain.steps.len());
+    for step in &chain.steps {
+        // Names are used in file names when saving,
+        // so they must not be able to reach outside the directory.
+        if step.id.is_empty()
+            || !step
+                .id
+                .chars()
+                .all(|character| character.is_alphanumeric() || matches!(character, '_' | '-'))
+        {
+            return Err(CliError::InvalidStep(step.id.clone()));
+        }
+        if step.id == INPUT || tasks.iter().any(|task| task.step.id == step.id) {
+            return Err(CliError::DuplicateStep(step.id.clone()));
+        }
+
+        let mut dependencies = Vec::new();
+        for captures in reference().captures_iter(step.input.as_deref().unwrap_or_default()) {
+            let name = &captures[1];
+            if name == INPUT || dependencies.iter().any(|dependency| dependency == name) {
+                continue;
+            }
+            if !tasks.iter().any(|task| task.step.id == name) {
+                return Err(CliError::UnknownStep {
+                    step: step.id.clone(),
+                    reference: name.to_owned(),
+                });
+            }
+            dependencies.push(name.to_owned());
+        }
+
+        let mut conversation = match &step.conversation {
+            Some(source) => load_conversation(chain.path.as_deref(), source)?,
+            None => Conversation::default(),
+        };
+        conversation.messages.extend(step.messages.iter().cloned());
+        tasks.push(Task {
+            step,
+            conversation,
+            dependencies,
+        });
+    }
+    Ok(tasks)
+}
+
+/// Load the [`Conversation`] of a [`Step`],
+/// resolving paths relative to the chain file.
+#[inline]
+fn load_conversation(chain: Option<&Path>, source: &str) -> Result<Conversation, CliError> {
+    if source.starts_with('@') {
+        return parse_conversation(source);
+    }
+
+    let path = chain
+        .and_then(Path::parent)
+        .map_or_else(|| PathBuf::from(source), |parent| parent.join(source));
+    parse_conversation(&path.to_string_lossy())
+}
+
+/// Fill in the references of a template.
+#[inline]
+fn render(template: &str, outputs: &HashMap<&str, String>) -> String {
+    reference()
+        .replace_all(template, |captures: &Captures<'_>| {
+            outputs.get(&captures[1]).cloned().unwrap_or_default()
+        })
+        .into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn steps_refer_to_earlier_steps() {
+        let chain = Conversation::from_reader(
+            "steps:
+  - id: outline
+    conversation: '@act-as-a-linux-terminal'
+    input: '{{ input }}'
+  - id: draft
+    messages: [{ role: system, content: You write drafts. }]
+    input: '{{outline}}'
+  - id: article
+    input: '{{draft}} {{outline}} {{draft}}'
+"
+            .as_bytes(),
+        )
+        .unwrap();
+        let tasks = plan(&chain).unwrap();
+        assert_eq!(tasks[0].dependencies, Vec::<String>::new());
+        assert_eq!(tasks[0].conversation.messages.len(), 1);
+        assert_eq!(tasks[2].dependencies, ["draft", "outline"]);
+
+        let outputs = HashMap::from([("draft", "D".to_owned()), ("outline", "O".to_owned())]);
+        assert_eq!(
+            render(tasks[2].step.input.as_deref().unwrap(), &outputs),
+            "D O D"
+        );
+
+        let chain = Conversation::from_reader(
+            "steps:
+  - id: first
+    input: '{{second}}'
+  - id: second
+"
+            .as_bytes(),
+        )
+        .unwrap();
+        assert!(matches!(
+            plan(&chain),
+            Err(CliError::UnknownStep { reference, .. }) if reference == "second"
+        ));
+
+        let chain = Conversation::from_reader("steps: [{id: ../../x}]".as_bytes()).unwrap();
+        assert!(matches!(plan(&chain), Err(CliError::InvalidStep(_))));
+    }
+}
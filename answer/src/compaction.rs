@@ -0,0 +1,239 @@
+//! Compaction of long [`Conversation`]s by summarization.
+//!
+//! Compaction is enabled per conversation file:
+//!
+//! ```yaml
+//! compaction:
+//!   threshold: 20
+//!   keep: 6
+//!   preserve: [system]
+//! messages:
+//!   - ...
+//! ```
+//!
+//! Once there are more than `threshold` messages,
+//! everything but the `keep` most recent messages and those with a
+//! `preserve`d role is summarized into a single system message named
+//! `memory`.
+//! Previous memories are summarized along with the older messages.
+
+use std::fmt::Write;
+
+use serde::Deserialize;
+use serde::Serialize;
+
+use crate::Bot;
+use crate::BotError;
+use crate::Conversation;
+use crate::Message;
+use crate::Role;
+
+/// Name of the system [`Message`] holding the summary.
+const MEMORY: &str = "memory";
+
+/// Settings for compacting a [`Conversation`] by summarization.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Compaction {
+    /// Number of messages above which the [`Conversation`] is compacted.
+    #[serde(default = "default_threshold")]
+    pub threshold: usize,
+    /// Number of most recent messages kept verbatim.
+    #[serde(default = "default_keep")]
+    pub keep: usize,
+    /// Instructions for summarizing older messages.
+    #[serde(default = "default_prompt")]
+    pub prompt: String,
+    /// Roles whose older messages are kept verbatim instead of summarized.
+    #[serde(default = "default_preserve")]
+    pub preserve: Vec<Role>,
+}
+
+impl Default for Compaction {
+    #[inline]
+    fn default() -> Self {
+        Self {
+            threshold: default_threshold(),
+            keep: default_keep(),
+            prompt: default_prompt(),
+            preserve: default_preserve(),
+        }
+    }
+}
+
+impl Compaction {
+    /// Compact a [`Conversation`] if it grew beyond the threshold.
+    ///
+    /// Returns whether the [`Conversation`] changed.
+    #[inline]
+    pub async fn compact(
+        &self,
+        bot: &Bot,
+        conversation: &mut Conversation,
+    ) -> Result<bool, BotError> {
+        let messages = &conversation.messages;
+        if messages.len() <= self.threshold {
+            return Ok(false);
+        }
+
+        let mut split = messages.len().saturating_sub(self.keep);
+        // Tool replies must follow the message calling the tools.
+        while split > 0
+            && messages
+                .get(split)
+                .is_some_and(|message| message.role == Role::Tool)
+        {
+            split -= 1;
+        }
+        let (older, recent) = messages.split_at(split);
+        let (summarized, preserved): (Vec<_>, Vec<_>) = older
+            .iter()
+            .cloned()
+            .partition(|message| is_memory(message) || !self.preserve.contains(&message.role));
+        if summarized.is_empty() {
+            return Ok(false);
+        }
+        log::debug!("summarizing {count} messages", count = summarized.len());
+
+        let mut transcript = String::new();
+        for message in &summarized {
+            if is_memory(message) {
+                writeln!(transcript, "(memory): {content}", content = message.content)
+            } else {
+                writeln!(
+                    transcript,
+                    "{role}: {content}",
+                    role = message.role,
+                    content = message.content
+                )
+            }
+            .expect("writing to a string should not fail");
+        }
+
+        let summary = bot
+            .reply_to_writer(
+                &Conversation {
+                    messages: vec![
+                        Message::from_system(self.prompt.clone()),
+                        Message::from_user(transcript),
+                    ],
+                    ..Conversation::default()
+                },
+                Vec::new(),
+            )
+            .await?;
+
+        let mut memory = Message::from_system(summary.trim());
+        memory.name = Some(MEMORY.to_owned());
+
+        let recent = recent.to_vec();
+        conversation.messages = preserved;
+        conversation.messages.push(memory);
+        conversation.messages.extend(recent);
+        Ok(true)
+    }
+}
+
+/// Determine whether a [`Message`] holds a previous summary.
+#[inline]
+pub fn is_memory(message: &Message) -> bool {
+    message.role == Role::System && message.name.as_deref() == Some(MEMORY)
+}
+
+/// Default for [`Compaction::threshold`].
+#[inline]
+const fn default_threshold() -> usize {
+    20
+}
+
+/// Default for [`Compaction::keep`].
+#[inline]
+const fn default_keep() -> usize {
+    6
+}
+
+/// Default for [`Compaction::prompt`].
+#[inline]
+fn default_prompt() -> String {
+    "Summarize the following conversation as a concise memory for the \
+     assistant, keeping names, facts, decisions and open questions. \
+     Reply with the summary only."
+        .to_owned()
+}
+
+/// Default for [`Compaction::preserve`].
+#[inline]
+fn default_preserve() -> Vec<Role> {
+    vec![Role::System]
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::executor::block_on;
+
+    use super::*;
+    use crate::provider::Provider;
+
+    #[test]
+    fn short_or_preserved_conversations_are_left_alone() {
+        let compaction = Compaction {
+            threshold: 2,
+            keep: 1,
+            ..Compaction::default()
+        };
+
+        let mut conversation = Conversation::default();
+        conversation.push(Message::from_system("You are a date of birth checker."));
+        conversation.push(Message::from_user("Malcolm X"));
+        assert!(!block_on(compaction.compact(&Bot::default(), &mut conversation)).unwrap());
+
+        conversation
+            .messages
+            .insert(1, Message::from_system("Be brief."));
+        assert!(!block_on(compaction.compact(&Bot::default(), &mut conversation)).unwrap());
+        assert_eq!(conversation.messages.len(), 3);
+    }
+
+    #[test]
+    fn tool_replies_are_kept_with_their_calls() {
+        let compaction = Compaction {
+            threshold: 3,
+            keep: 2,
+            ..Compaction::default()
+        };
+        let bot = Bot {
+            provider: Provider::Mock,
+            ..Bot::default()
+        };
+
+        let mut conversation = Conversation::from_reader(
+            "messages:
+- role: user
+  content: What is the weather in Paris?
+- role: assistant
+  content: ''
+  tool_calls:
+  - id: call_1
+    type: function
+    function: { name: weather, arguments: '{\"city\":\"Paris\"}' }
+- role: tool
+  content: Sunny.
+  tool_call_id: call_1
+- role: user
+  content: And tomorrow?
+"
+            .as_bytes(),
+        )
+        .unwrap();
+        assert!(block_on(compaction.compact(&bot, &mut conversation)).unwrap());
+        let roles: Vec<_> = conversation
+            .messages
+            .iter()
+            .map(|message| message.role)
+            .collect();
+        assert_eq!(
+            roles,
+            [Role::System, Role::Assistant, Role::Tool, Role::User]
+        );
+        assert!(is_memory(&conversation.messages[0]));
+    }
+}
@@ -0,0 +1,253 @@
+//! Comparing models side by side.
+//!
+//! The same [`Conversation`] is sent to every model concurrently,
+//! and the replies are shown in columns,
+//! together with the latency and token usage of each model.
+
+use std::io::IsTerminal;
+use std::io::Read;
+use std::io::{self};
+use std::time::Duration;
+use std::time::Instant;
+use std::time::SystemTime;
+
+use clap::Args;
+use console::measure_text_width;
+use console::pad_str;
+use console::style;
+use console::Alignment;
+use console::Term;
+use futures::future;
+use serde_json::json;
+use serde_json::Map;
+use serde_json::Value;
+
+use crate::history::Invocation;
+use crate::parse_conversation;
+use crate::Bot;
+use crate::BotError;
+use crate::CliError;
+use crate::Message;
+use crate::Reply;
+
+/// Separator between columns.
+const GUTTER: &str = " │ ";
+
+/// The result of asking a model.
+#[derive(Debug)]
+struct Run {
+    /// The model asked.
+    model: String,
+    /// How long the model took to reply.
+    latency: Duration,
+    /// The [`Reply`] of the model.
+    completion: Result<Reply, BotError>,
+}
+
+impl Run {
+    /// Get the text shown for this [`Run`],
+    /// which is either the reply or the error.
+    #[inline]
+    fn text(&self) -> String {
+        match &self.completion {
+            Ok(completion) => completion.text.clone(),
+            Err(err) => format!("error: {err}"),
+        }
+    }
+
+    /// Get a one-line summary of the latency and token usage of this
+    /// [`Run`].
+    #[inline]
+    fn stats(&self) -> String {
+        let latency = format!("{seconds:.2}s", seconds = self.latency.as_secs_f64());
+        match &self.completion {
+            Ok(Reply {
+                usage: Some(usage), ..
+            }) => format!(
+                "{latency}, {prompt}+{completion}={total} tokens",
+                prompt = usage.prompt_tokens,
+                completion = usage.completion_tokens,
+                total = usage.total_tokens
+            ),
+            _ => latency,
+        }
+    }
+
+    /// Convert this [`Run`] to JSON.
+    #[inline]
+    fn to_json(&self) -> Value {
+        let latency_ms = u64::try_from(self.latency.as_millis()).unwrap_or(u64::MAX);
+        match &self.completion {
+            Ok(completion) => json!({
+                "reply": completion.text,
+                "latency_ms": latency_ms,
+                "usage": completion.usage,
+            }),
+            Err(err) => json!({
+                "error": err.to_string(),
+                "latency_ms": latency_ms,
+            }),
+        }
+    }
+}
+
+/// Compare the replies of several models to a conversation.
+#[derive(Debug, Args)]
+pub struct CompareCommand {
+    /// Path to a conversation YAML file,
+    /// or `@name` of a prompt in the library.
+    ///
+    /// If the standard input is not a terminal,
+    /// it is added to the conversation as a user message.
+    conversation: String,
+
+    /// Model to ask,
+    /// given once per model.
+    #[arg(long = "model", value_name = "MODEL", required = true)]
+    models: Vec<String>,
+
+    /// Print a JSON object keyed by model instead of columns.
+    #[arg(long)]
+    json: bool,
+}
+
+impl CompareCommand {
+    /// Run this [`CompareCommand`].
+    #[inline]
+    pub async fn run(self, bot: &Bot) -> Result<(), CliError> {
+        let mut conversation = parse_conversation(&self.conversation)?;
+        let stdin = io::stdin();
+        if !stdin.is_terminal() {
+            let mut content = String::new();
+            stdin.lock().read_to_string(&mut content)?;
+            if !content.trim().is_empty() {
+                conversation.push(Message::from_user(content));
+            }
+        }
+
+        let started = SystemTime::now();
+        let runs = future::join_all(self.models.iter().map(|model| {
+            let conversation = &conversation;
+            async move {
+                // Every model gets the same request as any other reply.
+                let bot = Bot {
+                    model: Some(model.clone()),
+                    ..bot.clone()
+                };
+                let start = Instant::now();
+                let completion = bot.reply(conversation, Vec::new()).await;
+                Run {
+                    model: model.clone(),
+                    latency: start.elapsed(),
+                    completion,
+                }
+            }
+        }))
+        .await;
+
+        for run in &runs {
+            if let Ok(completion) = &run.completion {
+                bot.log(&Invocation {
+                    started,
+                    duration: run.latency,
+                    path: conversation.path.clone(),
+                    conversation: &conversation,
+                    parameters: json!({ "model": run.model, "compare": self.models }),
+                    reply: &completion.text,
+                    usage: completion.usage.clone(),
+                });
+            }
+        }
+
+        if self.json {
+            let object: Map<_, _> = runs
+                .iter()
+                .map(|run| (run.model.clone(), run.to_json()))
+                .collect();
+            println!("{}", serde_json::to_string_pretty(&object)?);
+        } else {
+            let (_, width) = Term::stdout().size();
+            print!("{}", columns(&runs, usize::from(width)));
+        }
+        Ok(())
+    }
+}
+
+/// Lay out [`Run`]s in columns fitting a width.
+#[inline]
+fn columns(runs: &[Run], width: usize) -> String {
+    let count = runs.len().max(1);
+    let column = width
+        .saturating_sub(measure_text_width(GUTTER) * (count - 1))
+        .checked_div(count)
+        .unwrap_or(width)
+        .max(16);
+
+    let cells: Vec<Vec<String>> = runs
+        .iter()
+        .map(|run| {
+            let mut lines = vec![
+                style(&run.model).bold().to_string(),
+                style(run.stats()).dim().to_string(),
+                "─".repeat(column),
+            ];
+            lines.extend(wrap(&run.text(), column));
+            lines
+        })
+        .collect();
+
+    let height = cells.iter().map(Vec::len).max().unwrap_or(0);
+    let mut output = String::new();
+    for row in 0..height {
+        let line: Vec<_> = cells
+            .iter()
+            .map(|lines| {
+                let cell = lines.get(row).map_or("", String::as_str);
+                pad_str(cell, column, Alignment::Left, Some("…")).into_owned()
+            })
+            .collect();
+        output.push_str(line.join(GUTTER).trim_end());
+        output.push('\n');
+    }
+    output
+}
+
+/// Wrap a text into lines of at most a width,
+/// breaking long words.
+#[inline]
+fn wrap(text: &str, width: usize) -> Vec<String> {
+    let mut lines = Vec::new();
+    for paragraph in text.lines() {
+        let mut line = String::new();
+        for word in paragraph.split_whitespace() {
+            if !line.is_empty() && measure_text_width(&line) + 1 + measure_text_width(word) > width
+            {
+                lines.push(std::mem::take(&mut line));
+            }
+            if !line.is_empty() {
+                line.push(' ');
+            }
+            for character in word.chars() {
+                if measure_text_width(&line) >= width {
+                    lines.push(std::mem::take(&mut line));
+                }
+                line.push(character);
+            }
+        }
+        lines.push(line);
+    }
+    lines
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn text_is_wrapped_to_width() {
+        assert_eq!(
+            wrap("May 19th, 1925.\n\nSupercalifragilistic", 8),
+            ["May", "19th,", "1925.", "", "Supercal", "ifragili", "stic"]
+        );
+    }
+}
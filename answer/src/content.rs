@@ -0,0 +1,241 @@
+//! Contents of [`Message`](crate::Message)s.
+//!
+//! Contents are usually plain text,
+//! but can also be a list of parts mixing text and images:
+//!
+//! ```yaml
+//! messages:
+//!   - content:
+//!       - text: What is in this picture?
+//!       - image: cat.png
+//!       - image: https://example.com/dog.jpg
+//! ```
+//!
+//! Local images are read relative to the conversation file and sent inline,
+//! base64-encoded.
+
+use std::env;
+use std::fmt;
+use std::fs;
+use std::io::{self};
+use std::path::Path;
+use std::path::PathBuf;
+
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use serde::Deserialize;
+use serde::Serialize;
+use serde_json::json;
+use serde_json::Value;
+
+/// The contents of a [`Message`](crate::Message).
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum Content {
+    /// Plain text.
+    Text(String),
+    /// A list of [`Part`]s.
+    Parts(Vec<Part>),
+}
+
+/// A part of [`Content`].
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum Part {
+    /// A piece of text.
+    Text {
+        /// The text.
+        text: String,
+    },
+    /// An image.
+    Image {
+        /// Path or URL of the image.
+        image: String,
+    },
+}
+
+impl Default for Content {
+    #[inline]
+    fn default() -> Self {
+        Self::Text(String::new())
+    }
+}
+
+impl fmt::Display for Content {
+    /// Format the text of this [`Content`],
+    /// with images replaced by placeholders.
+    #[inline]
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Text(text) => write!(f, "{text}"),
+            Self::Parts(parts) => {
+                for (index, part) in parts.iter().enumerate() {
+                    if index > 0 {
+                        writeln!(f)?;
+                    }
+                    match part {
+                        Part::Text { text } => write!(f, "{text}")?,
+                        Part::Image { image } => write!(f, "[image: {image}]")?,
+                    }
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl Content {
+    /// Determine whether this [`Content`] is empty.
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        match self {
+            Self::Text(text) => text.is_empty(),
+            Self::Parts(parts) => parts.is_empty(),
+        }
+    }
+
+    /// Determine whether this [`Content`] has images.
+    #[inline]
+    pub fn has_images(&self) -> bool {
+        match self {
+            Self::Text(_) => false,
+            Self::Parts(parts) => parts.iter().any(|part| matches!(part, Part::Image { .. })),
+        }
+    }
+
+    /// Determine whether this [`Content`] has images read from local files.
+    #[inline]
+    pub fn has_local_images(&self) -> bool {
+        match self {
+            Self::Text(_) => false,
+            Self::Parts(parts) => parts
+                .iter()
+                .any(|part| matches!(part, Part::Image { image } if !is_url(image))),
+        }
+    }
+
+    /// Transform the text of this [`Content`],
+    /// leaving images as they are.
+    #[inline]
+    pub fn map_text<F>(&self, mut f: F) -> Self
+    where
+        F: FnMut(&str) -> String,
+    {
+        match self {
+            Self::Text(text) => Self::Text(f(text)),
+            Self::Parts(parts) => Self::Parts(
+                parts
+                    .iter()
+                    .map(|part| match part {
+                        Part::Text { text } => Part::Text { text: f(text) },
+                        Part::Image { image } => Part::Image {
+                            image: image.clone(),
+                        },
+                    })
+                    .collect(),
+            ),
+        }
+    }
+
+    /// Attach images to this [`Content`],
+    /// resolving local ones against the current directory.
+    #[inline]
+    pub fn with_images<I>(self, images: I) -> io::Result<Self>
+    where
+        I: IntoIterator<Item = String>,
+    {
+        let mut parts = match self {
+            Self::Text(text) if text.is_empty() => Vec::new(),
+            Self::Text(text) => vec![Part::Text { text }],
+            Self::Parts(parts) => parts,
+        };
+        let directory = env::current_dir()?;
+        parts.extend(images.into_iter().map(|image| Part::Image {
+            image: if is_url(&image) {
+                image
+            } else {
+                directory.join(image).to_string_lossy().into_owned()
+            },
+        }));
+        Ok(Self::Parts(parts))
+    }
+
+    /// Convert this [`Content`] to the JSON expected by the chat completion
+    /// API,
+    /// reading and encoding local images relative to a directory, if any.
+    #[inline]
+    pub fn to_api(&self, directory: Option<&Path>) -> io::Result<Value> {
+        match self {
+            Self::Text(text) => Ok(Value::String(text.clone())),
+            Self::Parts(parts) => parts
+                .iter()
+                .map(|part| match part {
+                    Part::Text { text } => Ok(json!({ "type": "text", "text": text })),
+                    Part::Image { image } => Ok(json!({
+                        "type": "image_url",
+                        "image_url": { "url": image_url(image, directory)? },
+                    })),
+                })
+                .collect(),
+        }
+    }
+}
+
+/// Determine whether an image is given by URL rather than by path.
+#[inline]
+fn is_url(image: &str) -> bool {
+    ["http://", "https://", "data:"]
+        .iter()
+        .any(|scheme| image.starts_with(scheme))
+}
+
+/// Get a URL for an image,
+/// encoding local files,
+/// relative to a directory if any,
+/// as data URLs.
+#[inline]
+fn image_url(image: &str, directory: Option<&Path>) -> io::Result<String> {
+    if is_url(image) {
+        return Ok(image.to_owned());
+    }
+
+    let path = directory.map_or_else(|| PathBuf::from(image), |directory| directory.join(image));
+    let mime = match path
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .map(str::to_lowercase)
+        .as_deref()
+    {
+        Some("png") => "image/png",
+        Some("gif") => "image/gif",
+        Some("webp") => "image/webp",
+        _ => "image/jpeg",
+    };
+    Ok(format!(
+        "data:{mime};base64,{data}",
+        data = STANDARD.encode(fs::read(&path)?)
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parts_round_trip() {
+        let yaml = "- text: What is in this picture?\n- image: https://example.com/cat.png\n";
+        let content: Content = serde_yaml::from_str(yaml).unwrap();
+        assert!(content.has_images());
+        assert_eq!(serde_yaml::to_string(&content).unwrap(), yaml);
+
+        let api = content.to_api(None).unwrap();
+        assert_eq!(api[1]["image_url"]["url"], "https://example.com/cat.png");
+
+        let directory = env::temp_dir().join("answer-content-test");
+        fs::create_dir_all(&directory).unwrap();
+        fs::write(directory.join("cat.png"), b"cat").unwrap();
+        let content: Content = serde_yaml::from_str("- image: cat.png\n").unwrap();
+        let api = content.to_api(Some(&directory)).unwrap();
+        assert_eq!(api[0]["image_url"]["url"], "data:image/png;base64,Y2F0");
+    }
+}
@@ -0,0 +1,319 @@
+//! Editing files with instructions.
+//!
+//! The file and the instruction are sent along with a system prompt asking
+//! for search/replace blocks,
+//!
+//! ```text
+//! <<<<<<< SEARCH
+//! lines to find
+//! =======
+//! lines to put instead
+//! >>>>>>> REPLACE
+//! ```
+//!
+//! which are validated,
+//! previewed as a colored diff,
+//! and only written after confirmation.
+
+use std::fs;
+use std::io::BufRead;
+use std::io::Write;
+use std::io::{self};
+use std::path::PathBuf;
+use std::time::SystemTime;
+
+use clap::Args;
+use console::style;
+use console::Style;
+use serde_json::json;
+use similar::ChangeTag;
+use similar::TextDiff;
+use thiserror::Error;
+
+use crate::history::Invocation;
+use crate::Bot;
+use crate::CliError;
+use crate::Conversation;
+use crate::Message;
+use crate::Reply;
+
+/// System prompt asking for search/replace blocks.
+const PROMPT: &str = "You are an expert editor. \
+You will be given a file and an instruction. \
+Reply only with search/replace blocks that apply the instruction, \
+and nothing else. Each block has the following form:
+
+<<<<<<< SEARCH
+exact lines copied from the file
+=======
+lines that replace them
+>>>>>>> REPLACE
+
+Search lines must match the file exactly, including whitespace, \
+and must be unique in the file. \
+Include enough context lines to make them unique. \
+Use several small blocks rather than one large block.";
+
+/// Number of times the reply is requested before giving up.
+const ATTEMPTS: usize = 3;
+
+/// Marker starting the search part of a block.
+const SEARCH: &str = "<<<<<<< SEARCH";
+
+/// Marker separating the search and replace parts of a block.
+const DIVIDER: &str = "=======";
+
+/// Marker ending the replace part of a block.
+const REPLACE: &str = ">>>>>>> REPLACE";
+
+/// An error that came from applying search/replace blocks.
+#[derive(Debug, Error)]
+pub enum PatchError {
+    #[error("reply contains no search/replace blocks")]
+    Empty,
+    #[error("search/replace block {0} is not closed")]
+    Unclosed(usize),
+    #[error("search text of block {block} was found {count} times instead of once")]
+    Mismatch { block: usize, count: usize },
+}
+
+/// A search/replace block.
+#[derive(Debug, Default, PartialEq, Eq)]
+struct Block {
+    /// Lines to find, each ending in a newline.
+    search: String,
+    /// Lines to put instead, each ending in a newline.
+    replace: String,
+}
+
+/// Edit a file according to an instruction.
+#[derive(Debug, Args)]
+pub struct EditCommand {
+    /// Path to the file to edit.
+    path: PathBuf,
+
+    /// What to change in the file.
+    instruction: String,
+
+    /// Write the changes without asking for confirmation.
+    #[arg(short, long)]
+    yes: bool,
+}
+
+impl EditCommand {
+    /// Run this [`EditCommand`].
+    #[inline]
+    pub async fn run(self, bot: &Bot) -> Result<(), CliError> {
+        let original = fs::read_to_string(&self.path)?;
+
+        let mut conversation = Conversation::default();
+        conversation.push(Message::from_system(PROMPT));
+        conversation.push(Message::from_user(format!(
+            "File: {path}\n\n```\n{original}\n```\n\nInstruction: {instruction}",
+            path = self.path.display(),
+            instruction = self.instruction
+        )));
+
+        let mut attempt = 1;
+        let edited = loop {
+            let started = SystemTime::now();
+            let Reply { text: reply, usage } = bot.reply(&conversation, Vec::new()).await?;
+            bot.log(&Invocation {
+                started,
+                duration: started.elapsed().unwrap_or_default(),
+                path: None,
+                conversation: &conversation,
+                parameters: json!({
+                    "model": bot.model(&conversation),
+                    "max_tokens": bot.max_tokens,
+                    "edit": self.path,
+                    "attempt": attempt,
+                }),
+                reply: &reply,
+                usage,
+            });
+            match parse(&reply).and_then(|blocks| apply(&original, &blocks)) {
+                Ok(edited) => break edited,
+                Err(err) if attempt < ATTEMPTS => {
+                    log::warn!("retrying: {err}");
+                    conversation.push(Message::from_assistant(reply));
+                    conversation.push(Message::from_user(format!(
+                        "The blocks could not be applied: {err}. \
+                         Please reply with corrected blocks."
+                    )));
+                    attempt += 1;
+                }
+                Err(err) => return Err(err.into()),
+            }
+        };
+
+        if edited == original {
+            eprintln!("No changes.");
+            return Ok(());
+        }
+
+        preview(&self.path.display().to_string(), &original, &edited)?;
+        if self.yes || confirm("Apply these changes?")? {
+            fs::write(&self.path, edited)?;
+        }
+        Ok(())
+    }
+}
+
+/// Parse the search/replace [`Block`]s of a reply.
+///
+/// Text outside blocks is ignored.
+#[inline]
+fn parse(reply: &str) -> Result<Vec<Block>, PatchError> {
+    enum State {
+        Outside,
+        Search,
+        Replace,
+    }
+
+    let mut blocks = Vec::new();
+    let mut block = Block::default();
+    let mut state = State::Outside;
+    for line in reply.lines() {
+        match (&state, line.trim_end()) {
+            (State::Outside, SEARCH) => state = State::Search,
+            (State::Outside, _) => {}
+            (State::Search, DIVIDER) => state = State::Replace,
+            (State::Search, _) => {
+                block.search.push_str(line);
+                block.search.push('\n');
+            }
+            (State::Replace, REPLACE) => {
+                blocks.push(std::mem::take(&mut block));
+                state = State::Outside;
+            }
+            (State::Replace, _) => {
+                block.replace.push_str(line);
+                block.replace.push('\n');
+            }
+        }
+    }
+
+    match state {
+        State::Outside if blocks.is_empty() => Err(PatchError::Empty),
+        State::Outside => Ok(blocks),
+        State::Search | State::Replace => Err(PatchError::Unclosed(blocks.len() + 1)),
+    }
+}
+
+/// Apply search/replace [`Block`]s to a text, in order.
+///
+/// The search text of each block must start at a line and be found exactly
+/// once.
+/// An empty search text is only allowed for an empty text.
+#[inline]
+fn apply(text: &str, blocks: &[Block]) -> Result<String, PatchError> {
+    let terminated = text.is_empty() || text.ends_with('\n');
+    let mut text = if terminated {
+        text.to_owned()
+    } else {
+        format!("{text}\n")
+    };
+
+    for (index, block) in blocks.iter().enumerate() {
+        if block.search.is_empty() {
+            if !text.is_empty() {
+                return Err(PatchError::Mismatch {
+                    block: index + 1,
+                    count: 0,
+                });
+            }
+            text.clone_from(&block.replace);
+            continue;
+        }
+
+        let matches: Vec<_> = text
+            .match_indices(&block.search)
+            .map(|(start, _)| start)
+            .filter(|&start| start == 0 || text[..start].ends_with('\n'))
+            .collect();
+        let [start] = matches[..] else {
+            return Err(PatchError::Mismatch {
+                block: index + 1,
+                count: matches.len(),
+            });
+        };
+        text.replace_range(start..start + block.search.len(), &block.replace);
+    }
+
+    if !terminated && text.ends_with('\n') {
+        text.pop();
+    }
+    Ok(text)
+}
+
+/// Print a colored unified diff between two versions of a file.
+#[inline]
+fn preview(path: &str, old: &str, new: &str) -> io::Result<()> {
+    let mut stdout = io::stdout().lock();
+    writeln!(stdout, "{}", style(format!("--- a/{path}")).bold())?;
+    writeln!(stdout, "{}", style(format!("+++ b/{path}")).bold())?;
+
+    let diff = TextDiff::from_lines(old, new);
+    for hunk in diff.unified_diff().iter_hunks() {
+        writeln!(stdout, "{}", style(hunk.header()).cyan())?;
+        for change in hunk.iter_changes() {
+            let (sign, style) = match change.tag() {
+                ChangeTag::Delete => ("-", Style::new().red()),
+                ChangeTag::Insert => ("+", Style::new().green()),
+                ChangeTag::Equal => (" ", Style::new().dim()),
+            };
+            write!(
+                stdout,
+                "{}",
+                style.apply_to(format!("{sign}{value}", value = change.value()))
+            )?;
+            if change.missing_newline() {
+                writeln!(stdout)?;
+            }
+        }
+    }
+    stdout.flush()
+}
+
+/// Ask the user a yes/no question on the terminal.
+///
+/// Anything but an explicit yes is a no.
+#[inline]
+fn confirm(question: &str) -> io::Result<bool> {
+    eprint!("{question} [y/N] ");
+    io::stderr().flush()?;
+
+    let mut answer = String::new();
+    io::stdin().lock().read_line(&mut answer)?;
+    Ok(matches!(answer.trim().to_lowercase().as_str(), "y" | "yes"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn blocks_apply_once_at_line_starts() {
+        let reply = "Sure!\n<<<<<<< SEARCH\nb = 2\n=======\nb = 3\n>>>>>>> REPLACE\n";
+        let blocks = parse(reply).unwrap();
+        assert_eq!(apply("a = 1\nb = 2", &blocks).unwrap(), "a = 1\nb = 3");
+        assert!(matches!(
+            apply("b = 2\nb = 2\n", &blocks),
+            Err(PatchError::Mismatch { block: 1, count: 2 })
+        ));
+        assert!(matches!(
+            apply("ab = 2\n", &blocks),
+            Err(PatchError::Mismatch { block: 1, count: 0 })
+        ));
+    }
+
+    #[test]
+    fn unclosed_blocks_are_rejected() {
+        assert!(matches!(
+            parse("<<<<<<< SEARCH\na\n=======\n"),
+            Err(PatchError::Unclosed(1))
+        ));
+        assert!(matches!(parse("no blocks"), Err(PatchError::Empty)));
+    }
+}
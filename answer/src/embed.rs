@@ -0,0 +1,300 @@
+//! Embeddings and a local vector [`Store`].
+//!
+//! The [`Store`] is a single JSON file holding the embedding model,
+//! and for each [`Entry`],
+//! the embedded text, where it came from and its vector.
+
+use std::fs::File;
+use std::fs::{self};
+use std::io::BufReader;
+use std::io::BufWriter;
+use std::io::{self};
+use std::num::NonZeroUsize;
+use std::path::Path;
+use std::path::PathBuf;
+
+use clap::Args;
+use serde::Deserialize;
+use serde::Serialize;
+use tokio::io::AsyncReadExt;
+
+use crate::snippet;
+use crate::Bot;
+use crate::CliError;
+
+/// Embedding model used when creating a new [`Store`].
+pub const DEFAULT_MODEL: &str = "text-embedding-ada-002";
+
+/// Path of the [`Store`] used when none is given.
+pub const DEFAULT_STORE: &str = ".answer/index.json";
+
+/// Maximum number of texts embedded in a single request.
+const CHUNK_SIZE: usize = 64;
+
+/// A local on-disk store of embedding vectors.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct Store {
+    /// The model that computed the vectors in this [`Store`].
+    pub model: String,
+    /// [`Entry`]s in this [`Store`].
+    #[serde(default)]
+    pub entries: Vec<Entry>,
+}
+
+/// An embedded text in a [`Store`].
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Entry {
+    /// Where the text came from (e.g., a file path).
+    pub source: String,
+    /// The embedded text.
+    pub text: String,
+    /// The embedding vector of the text.
+    pub vector: Vec<f32>,
+}
+
+impl Store {
+    /// Create an empty [`Store`] for the given model.
+    #[inline]
+    pub fn new<M>(model: M) -> Self
+    where
+        M: Into<String>,
+    {
+        Self {
+            model: model.into(),
+            entries: Vec::new(),
+        }
+    }
+
+    /// Load a [`Store`] from a file [`Path`], if it exists.
+    #[inline]
+    pub fn open(path: &Path) -> Result<Option<Self>, CliError> {
+        match File::open(path) {
+            Ok(file) => Ok(Some(serde_json::from_reader(BufReader::new(file))?)),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Save this [`Store`] to a file [`Path`],
+    /// creating parent directories as needed.
+    ///
+    /// The file is replaced atomically,
+    /// so an interruption never leaves a truncated [`Store`] behind.
+    #[inline]
+    pub fn save(&self, path: &Path) -> Result<(), CliError> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        let temporary = path.with_extension("tmp");
+        let mut writer = BufWriter::new(File::create(&temporary)?);
+        serde_json::to_writer(&mut writer, self)?;
+        // Dropping the writer would silently ignore errors in the last write.
+        writer
+            .into_inner()
+            .map_err(io::IntoInnerError::into_error)?
+            .sync_all()?;
+        fs::rename(temporary, path)?;
+        Ok(())
+    }
+
+    /// Embed texts with the model of this [`Store`].
+    #[inline]
+    pub async fn embed(&self, bot: &Bot, texts: Vec<String>) -> Result<Vec<Vec<f32>>, CliError> {
+        embed(bot, &self.model, texts).await
+    }
+
+    /// Find the `k` [`Entry`]s in this [`Store`] most similar to a query
+    /// vector.
+    #[inline]
+    pub fn search(&self, query: &[f32], k: usize) -> Vec<(f32, &Entry)> {
+        nearest(query, &self.entries, k)
+    }
+}
+
+/// Compute embeddings for standard input lines or files and store them.
+#[derive(Debug, Args)]
+pub struct EmbedCommand {
+    /// Files to embed, one entry per file.
+    ///
+    /// If no file is given,
+    /// each non-empty line of the standard input is embedded instead.
+    files: Vec<PathBuf>,
+
+    /// Path to the store.
+    #[arg(long, default_value = DEFAULT_STORE)]
+    store: PathBuf,
+
+    /// Embedding model.
+    ///
+    /// Defaults to the model of an existing store.
+    #[arg(long)]
+    model: Option<String>,
+}
+
+impl EmbedCommand {
+    /// Run this [`EmbedCommand`].
+    #[inline]
+    pub async fn run(self, bot: &Bot) -> Result<(), CliError> {
+        let mut store = match (Store::open(&self.store)?, self.model) {
+            (Some(store), Some(model)) if store.model != model => {
+                return Err(CliError::ModelMismatch {
+                    store: store.model,
+                    requested: model,
+                })
+            }
+            (Some(store), _) => store,
+            (None, model) => Store::new(model.as_deref().unwrap_or(DEFAULT_MODEL)),
+        };
+
+        let mut inputs = Vec::new();
+        if self.files.is_empty() {
+            let mut content = String::new();
+            tokio::io::stdin().read_to_string(&mut content).await?;
+
+            for (index, line) in content.lines().enumerate() {
+                if !line.trim().is_empty() {
+                    inputs.push((format!("stdin:{line}", line = index + 1), line.to_owned()));
+                }
+            }
+        } else {
+            for path in &self.files {
+                inputs.push((path.display().to_string(), fs::read_to_string(path)?));
+            }
+        }
+
+        // Texts already in the store are not embedded again.
+        inputs.retain(|(source, text)| {
+            !store
+                .entries
+                .iter()
+                .any(|entry| entry.source == *source && entry.text == *text)
+        });
+        log::debug!("embedding {count} new texts", count = inputs.len());
+
+        let vectors = store
+            .embed(bot, inputs.iter().map(|(_, text)| text.clone()).collect())
+            .await?;
+        for ((source, text), vector) in inputs.into_iter().zip(vectors) {
+            // Files replace their previous contents.
+            if !self.files.is_empty() {
+                store.entries.retain(|entry| entry.source != source);
+            }
+            store.entries.push(Entry {
+                source,
+                text,
+                vector,
+            });
+        }
+
+        store.save(&self.store)
+    }
+}
+
+/// Find the stored items most similar to a query.
+#[derive(Debug, Args)]
+pub struct SearchCommand {
+    /// The query text.
+    query: String,
+
+    /// Number of results.
+    #[arg(short, long, default_value = "5")]
+    k: NonZeroUsize,
+
+    /// Path to the store.
+    #[arg(long, default_value = DEFAULT_STORE)]
+    store: PathBuf,
+}
+
+impl SearchCommand {
+    /// Run this [`SearchCommand`].
+    #[inline]
+    pub async fn run(self, bot: &Bot) -> Result<(), CliError> {
+        let store =
+            Store::open(&self.store)?.ok_or_else(|| CliError::StoreNotFound(self.store.clone()))?;
+
+        let query = store
+            .embed(bot, vec![self.query])
+            .await?
+            .pop()
+            .unwrap_or_default();
+        for (score, entry) in store.search(&query, self.k.get()) {
+            println!(
+                "{score:.4}\t{source}\t{snippet}",
+                source = entry.source,
+                snippet = snippet(&entry.text)
+            );
+        }
+        Ok(())
+    }
+}
+
+/// Embed texts with the given model,
+/// in as few requests as possible.
+#[inline]
+pub async fn embed(bot: &Bot, model: &str, texts: Vec<String>) -> Result<Vec<Vec<f32>>, CliError> {
+    let mut vectors = Vec::with_capacity(texts.len());
+    for chunk in texts.chunks(CHUNK_SIZE) {
+        vectors.extend(bot.embed(model, chunk.to_vec()).await?);
+    }
+    Ok(vectors)
+}
+
+/// Find the `k` [`Entry`]s most similar to a query vector,
+/// most similar first.
+#[inline]
+pub fn nearest<'a, I>(query: &[f32], entries: I, k: usize) -> Vec<(f32, &'a Entry)>
+where
+    I: IntoIterator<Item = &'a Entry>,
+{
+    let mut results: Vec<_> = entries
+        .into_iter()
+        .map(|entry| (cosine_similarity(query, &entry.vector), entry))
+        .collect();
+    results.sort_by(|(a, _), (b, _)| b.total_cmp(a));
+    results.truncate(k);
+    results
+}
+
+/// Compute the cosine similarity between two vectors.
+///
+/// Zero vectors are not similar to anything.
+#[inline]
+pub fn cosine_similarity(a: &[f32], b: &[f32]) -> f32 {
+    let dot: f32 = a.iter().zip(b).map(|(a, b)| a * b).sum();
+    let norm_a = a.iter().map(|a| a * a).sum::<f32>().sqrt();
+    let norm_b = b.iter().map(|b| b * b).sum::<f32>().sqrt();
+    if norm_a == 0.0 || norm_b == 0.0 {
+        0.0
+    } else {
+        dot / (norm_a * norm_b)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cosine_similarity_is_scale_invariant() {
+        assert!((cosine_similarity(&[1.0, 2.0], &[2.0, 4.0]) - 1.0).abs() < 1e-6);
+        assert!(cosine_similarity(&[1.0, 0.0], &[0.0, 1.0]).abs() < 1e-6);
+        assert!(cosine_similarity(&[0.0, 0.0], &[1.0, 1.0]).abs() < f32::EPSILON);
+    }
+
+    #[test]
+    fn search_ranks_most_similar_first() {
+        let mut store = Store::new(DEFAULT_MODEL);
+        for (source, vector) in [("a", vec![1.0, 0.0]), ("b", vec![0.7, 0.7])] {
+            store.entries.push(Entry {
+                source: source.to_owned(),
+                text: String::new(),
+                vector,
+            });
+        }
+
+        let results = store.search(&[0.0, 1.0], 1);
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].1.source, "b");
+    }
+}
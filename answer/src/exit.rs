@@ -0,0 +1,418 @@
+//! Exit codes and error reporting.
+//!
+//! Every error falls into an [`ErrorKind`] with its own exit code,
+//! so that scripts can react to failures,
+//! and can be reported as text or as JSON.
+
+use std::ffi::OsString;
+use std::fmt;
+use std::io::{self};
+
+use async_openai::error::ApiError;
+use async_openai::error::OpenAIError;
+use clap::ValueEnum;
+use reqwest::StatusCode;
+use rustyline::error::ReadlineError;
+use serde::Deserialize;
+use serde_json::json;
+
+use crate::mcp::McpError;
+use crate::BotError;
+use crate::CliError;
+
+/// A format for reporting errors.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum ErrorFormat {
+    /// Plain text.
+    #[default]
+    Text,
+    /// A JSON object with the kind, exit code and message of the error.
+    Json,
+}
+
+/// A kind of error,
+/// with its own exit code.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ErrorKind {
+    /// Any other failure.
+    Other,
+    /// The command-line arguments are invalid.
+    Usage,
+    /// Content was flagged by moderation.
+    Flagged,
+    /// The API key is not set.
+    MissingKey,
+    /// The API key was rejected.
+    Auth,
+    /// Too many requests were made, or the quota was exceeded.
+    RateLimited,
+    /// The conversation does not fit in the context of the model.
+    ContextTooLong,
+    /// Files could not be parsed, or names could not be found.
+    InvalidInput,
+    /// The API could not be reached.
+    Network,
+    /// A budget would be exceeded.
+    OverBudget,
+    /// The user interrupted the program.
+    Cancelled,
+}
+
+impl ErrorKind {
+    /// Get the exit code of this [`ErrorKind`].
+    #[inline]
+    pub const fn code(self) -> u8 {
+        match self {
+            Self::Other => 1,
+            Self::Usage => 2,
+            Self::Flagged => 3,
+            Self::MissingKey => 4,
+            Self::Auth => 5,
+            Self::RateLimited => 6,
+            Self::ContextTooLong => 7,
+            Self::InvalidInput => 8,
+            Self::Network => 9,
+            Self::OverBudget => 10,
+            Self::Cancelled => 130,
+        }
+    }
+
+    /// Classify an HTTP status code.
+    #[inline]
+    fn from_status(status: StatusCode) -> Option<Self> {
+        match status {
+            StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => Some(Self::Auth),
+            StatusCode::TOO_MANY_REQUESTS => Some(Self::RateLimited),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for ErrorKind {
+    #[inline]
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Other => write!(f, "other"),
+            Self::Usage => write!(f, "usage"),
+            Self::Flagged => write!(f, "flagged"),
+            Self::MissingKey => write!(f, "missing_key"),
+            Self::Auth => write!(f, "auth"),
+            Self::RateLimited => write!(f, "rate_limited"),
+            Self::ContextTooLong => write!(f, "context_too_long"),
+            Self::InvalidInput => write!(f, "invalid_input"),
+            Self::Network => write!(f, "network"),
+            Self::OverBudget => write!(f, "over_budget"),
+            Self::Cancelled => write!(f, "cancelled"),
+        }
+    }
+}
+
+impl BotError {
+    /// Get the [`ErrorKind`] of this [`BotError`].
+    #[inline]
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            Self::Var(_) => ErrorKind::MissingKey,
+            Self::OpenAI(err) => openai_kind(err),
+            Self::Status { status, .. } => {
+                ErrorKind::from_status(*status).unwrap_or(ErrorKind::Other)
+            }
+            Self::Flagged(_) => ErrorKind::Flagged,
+            Self::NotRecorded(_) => ErrorKind::InvalidInput,
+            Self::Budget(err) => err.kind(),
+            Self::Mcp(McpError::Http(err)) => reqwest_kind(err),
+            Self::Io(err) => io_kind(err),
+            Self::Mcp(_) => ErrorKind::Other,
+        }
+    }
+}
+
+impl CliError {
+    /// Get the [`ErrorKind`] of this [`CliError`].
+    #[inline]
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            Self::Bot(err) => err.kind(),
+            Self::StepFailed { source, .. } => source.kind(),
+            Self::OverBudget { .. } => ErrorKind::OverBudget,
+            Self::Cancelled | Self::Readline(ReadlineError::Interrupted | ReadlineError::Eof) => {
+                ErrorKind::Cancelled
+            }
+            Self::Yaml(_)
+            | Self::Json(_)
+            | Self::Jsonl { .. }
+            | Self::Regex(_)
+            | Self::ModelMismatch { .. }
+            | Self::StoreNotFound(_)
+            | Self::BranchNotFound(_)
+            | Self::PromptNotFound(_)
+            | Self::InvocationNotFound(_)
+            | Self::LintFailed(_)
+            | Self::DuplicateStep(_)
+            | Self::InvalidStep(_)
+            | Self::UnknownStep { .. }
+            | Self::UnsupportedInChain(_)
+            | Self::UnknownExtractor(_) => ErrorKind::InvalidInput,
+            Self::Io(err) => io_kind(err),
+            Self::Patch(_)
+            | Self::Readline(_)
+            | Self::Command(_)
+            | Self::NoConfigDir
+            | Self::Editor(_)
+            | Self::TestsFailed { .. }
+            | Self::Sqlite(_)
+            | Self::NoDataDir
+            | Self::NothingExtracted(_) => ErrorKind::Other,
+        }
+    }
+
+    /// Report this [`CliError`] on the standard error.
+    #[inline]
+    pub fn report(&self, format: ErrorFormat) {
+        match format {
+            ErrorFormat::Json => report_json(self.kind(), &self.to_string()),
+            ErrorFormat::Text => eprintln!("Error: {self}"),
+        }
+    }
+}
+
+/// Report an error as a JSON object on the standard error.
+#[inline]
+fn report_json(kind: ErrorKind, message: &str) {
+    let error = json!({
+        "error": {
+            "kind": kind.to_string(),
+            "code": kind.code(),
+            "message": message,
+        },
+    });
+    eprintln!("{error}");
+}
+
+/// Report a [`clap::Error`] about the command-line arguments,
+/// in the [`ErrorFormat`] they ask for,
+/// and get the exit code.
+///
+/// The arguments could not be parsed,
+/// so `--error-format` is looked for by hand.
+#[inline]
+pub fn report_usage(err: &clap::Error, args: &[OsString]) -> u8 {
+    let code = u8::try_from(err.exit_code()).unwrap_or(ErrorKind::Usage.code());
+    if !err.use_stderr() {
+        // Help and version are not errors.
+        if let Err(err) = err.print() {
+            log::warn!("could not print the help: {err}");
+        }
+        return code;
+    }
+    match error_format(args) {
+        ErrorFormat::Json => {
+            let message = err.to_string();
+            let message = message.lines().next().unwrap_or_default();
+            report_json(
+                ErrorKind::Usage,
+                message.strip_prefix("error: ").unwrap_or(message),
+            );
+        }
+        ErrorFormat::Text => {
+            if let Err(err) = err.print() {
+                log::warn!("could not print the error: {err}");
+            }
+        }
+    }
+    code
+}
+
+/// Find the [`ErrorFormat`] asked for in command-line arguments
+/// that could not be parsed.
+#[inline]
+fn error_format(args: &[OsString]) -> ErrorFormat {
+    let mut args = args.iter().filter_map(|arg| arg.to_str());
+    while let Some(arg) = args.next() {
+        let value = match arg.strip_prefix("--error-format") {
+            Some("") => args.next(),
+            Some(value) => value.strip_prefix('='),
+            None => continue,
+        };
+        if let Some(format) = value.and_then(|value| ErrorFormat::from_str(value, true).ok()) {
+            return format;
+        }
+    }
+    ErrorFormat::default()
+}
+
+/// The body of a failed API request.
+#[derive(Debug, Deserialize)]
+struct ErrorBody {
+    /// The error the API returned.
+    error: ApiError,
+}
+
+/// Get the [`BotError`] of a request that failed with a status.
+///
+/// The error the API returned in the body is kept if it tells more than the
+/// status,
+/// such as the conversation being too long.
+#[inline]
+pub fn stream_error(status: StatusCode, body: &[u8]) -> BotError {
+    match serde_json::from_slice::<ErrorBody>(body) {
+        Ok(ErrorBody { error }) => {
+            let message = format!("invalid status code {status}: {}", error.message);
+            let err = OpenAIError::ApiError(error);
+            if openai_kind(&err) == ErrorKind::Other {
+                BotError::Status { status, message }
+            } else {
+                BotError::OpenAI(err)
+            }
+        }
+        Err(_) => BotError::Status {
+            status,
+            message: format!("invalid status code {status}"),
+        },
+    }
+}
+
+/// Classify an [`OpenAIError`].
+#[inline]
+fn openai_kind(err: &OpenAIError) -> ErrorKind {
+    match err {
+        OpenAIError::Reqwest(err) => reqwest_kind(err),
+        OpenAIError::ApiError(err) => {
+            let code = err
+                .code
+                .as_ref()
+                .and_then(|code| code.as_str())
+                .unwrap_or_default();
+            match (err.r#type.as_str(), code) {
+                (_, "context_length_exceeded") => ErrorKind::ContextTooLong,
+                (_, "invalid_api_key") | ("authentication_error", _) => ErrorKind::Auth,
+                (_, "rate_limit_exceeded") | ("insufficient_quota" | "rate_limit_error", _) => {
+                    ErrorKind::RateLimited
+                }
+                _ => ErrorKind::Other,
+            }
+        }
+        // Failed statuses are reported as [`BotError::Status`],
+        // so stream errors are only malformed events.
+        OpenAIError::StreamError(_)
+        | OpenAIError::JSONDeserialize(_)
+        | OpenAIError::FileSaveError(_)
+        | OpenAIError::FileReadError(_)
+        | OpenAIError::InvalidArgument(_) => ErrorKind::Other,
+    }
+}
+
+/// Classify an [`io::Error`].
+///
+/// Files that cannot be found or read as text are invalid input.
+#[inline]
+fn io_kind(err: &io::Error) -> ErrorKind {
+    match err.kind() {
+        io::ErrorKind::NotFound | io::ErrorKind::InvalidData | io::ErrorKind::InvalidInput => {
+            ErrorKind::InvalidInput
+        }
+        _ => ErrorKind::Other,
+    }
+}
+
+/// Classify a [`reqwest::Error`].
+#[inline]
+fn reqwest_kind(err: &reqwest::Error) -> ErrorKind {
+    match err.status() {
+        Some(status) => ErrorKind::from_status(status).unwrap_or(ErrorKind::Other),
+        None if err.is_connect() || err.is_timeout() || err.is_request() => ErrorKind::Network,
+        None => ErrorKind::Other,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use async_openai::error::ApiError;
+
+    use super::*;
+
+    #[test]
+    fn errors_are_classified() {
+        let api = |r#type: &str, code: &str| {
+            CliError::Bot(BotError::OpenAI(OpenAIError::ApiError(ApiError {
+                message: String::new(),
+                r#type: r#type.to_owned(),
+                param: None,
+                code: Some(json!(code)),
+            })))
+        };
+        assert_eq!(
+            api("invalid_request_error", "context_length_exceeded").kind(),
+            ErrorKind::ContextTooLong
+        );
+        assert_eq!(
+            api("invalid_request_error", "invalid_api_key").kind(),
+            ErrorKind::Auth
+        );
+        assert_eq!(
+            api("insufficient_quota", "insufficient_quota").kind(),
+            ErrorKind::RateLimited
+        );
+
+        assert_eq!(
+            CliError::Bot(BotError::OpenAI(OpenAIError::StreamError(
+                "Invalid status code: 429 Too Many Requests".to_owned(),
+            )))
+            .kind(),
+            ErrorKind::Other
+        );
+        assert_eq!(
+            CliError::Io(io::Error::from(io::ErrorKind::NotFound)).kind(),
+            ErrorKind::InvalidInput
+        );
+        assert_eq!(
+            CliError::Io(io::Error::from(io::ErrorKind::BrokenPipe)).kind(),
+            ErrorKind::Other
+        );
+    }
+
+    #[test]
+    fn failed_streams_are_classified_by_body() {
+        let kind = |status: StatusCode, body: &str| {
+            CliError::Bot(stream_error(status, body.as_bytes())).kind()
+        };
+        assert_eq!(
+            kind(
+                StatusCode::BAD_REQUEST,
+                r#"{"error": {"message": "This model's maximum context length is 4097 tokens.",
+                              "type": "invalid_request_error", "param": "messages",
+                              "code": "context_length_exceeded"}}"#
+            ),
+            ErrorKind::ContextTooLong
+        );
+        assert_eq!(
+            kind(
+                StatusCode::TOO_MANY_REQUESTS,
+                r#"{"error": {"message": "Slow down.", "type": "requests", "code": null}}"#
+            ),
+            ErrorKind::RateLimited
+        );
+        assert_eq!(
+            kind(StatusCode::UNAUTHORIZED, "<html>Unauthorized</html>"),
+            ErrorKind::Auth
+        );
+        assert_eq!(
+            kind(StatusCode::BAD_GATEWAY, "Bad Gateway"),
+            ErrorKind::Other
+        );
+
+        assert_eq!(CliError::Cancelled.kind().code(), 130);
+        assert_eq!(
+            error_format(&["answer", "-v", "--error-format=json", "--bad"].map(OsString::from)),
+            ErrorFormat::Json
+        );
+        assert_eq!(
+            error_format(&["answer", "--error-format", "json"].map(OsString::from)),
+            ErrorFormat::Json
+        );
+        assert_eq!(
+            CliError::PromptNotFound(String::new()).kind(),
+            ErrorKind::InvalidInput
+        );
+    }
+}
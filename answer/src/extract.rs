@@ -0,0 +1,180 @@
+//! Extraction of parts of answers.
+//!
+//! With `--extract`,
+//! only part of the complete answer is printed:
+//!
+//! - `code`: the fenced code blocks,
+//!   or `code:LANG` those in a given language,
+//! - `json`: the first valid JSON object or array,
+//! - `regex:PATTERN`: the matches of a regular expression,
+//!   or of its first group if it has any,
+//!   one per line.
+//!
+//! ```console
+//! $ echo "Write a script listing the largest files" | answer --extract code:bash > script.sh
+//! ```
+
+use std::fmt;
+
+use regex::Regex;
+use serde_json::Deserializer;
+use serde_json::Value;
+
+use crate::CliError;
+
+/// Markers opening and closing fenced code blocks.
+const FENCES: [&str; 2] = ["```", "~~~"];
+
+/// What to extract from an answer.
+#[derive(Clone, Debug)]
+pub enum Extractor {
+    /// Fenced code blocks,
+    /// in a given language if any.
+    Code(Option<String>),
+    /// The first valid JSON object or array.
+    Json,
+    /// Matches of a regular expression.
+    Regex(Regex),
+}
+
+/// Parse an [`Extractor`] from a command-line argument.
+#[inline]
+pub fn parse_extractor(arg: &str) -> Result<Extractor, CliError> {
+    let (kind, parameter) = arg
+        .split_once(':')
+        .map_or((arg, None), |(kind, parameter)| (kind, Some(parameter)));
+    match (kind, parameter) {
+        ("code", None) => Ok(Extractor::Code(None)),
+        ("code", Some(language)) => Ok(Extractor::Code(Some(language.to_owned()))),
+        ("json", None) => Ok(Extractor::Json),
+        ("regex", Some(pattern)) => Ok(Extractor::Regex(Regex::new(pattern)?)),
+        _ => Err(CliError::UnknownExtractor(arg.to_owned())),
+    }
+}
+
+impl Extractor {
+    /// Extract from an answer,
+    /// failing with [`CliError::NothingExtracted`] if nothing is found.
+    #[inline]
+    pub fn extract(&self, answer: &str) -> Result<String, CliError> {
+        let extracted = match self {
+            Self::Code(language) => code_blocks(answer, language.as_deref()),
+            Self::Json => first_json(answer).map(|json| format!("{json}\n")),
+            Self::Regex(regex) => {
+                let matches: Vec<&str> = regex
+                    .captures_iter(answer)
+                    .filter_map(|captures| captures.get(1).or_else(|| captures.get(0)))
+                    .map(|found| found.as_str())
+                    .collect();
+                (!matches.is_empty()).then(|| format!("{matches}\n", matches = matches.join("\n")))
+            }
+        };
+        extracted.ok_or_else(|| CliError::NothingExtracted(self.to_string()))
+    }
+}
+
+impl fmt::Display for Extractor {
+    #[inline]
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Code(None) => write!(f, "code"),
+            Self::Code(Some(language)) => write!(f, "code:{language}"),
+            Self::Json => write!(f, "json"),
+            Self::Regex(regex) => write!(f, "regex:{regex}"),
+        }
+    }
+}
+
+/// Get the contents of the fenced code blocks of a text,
+/// in a given language if any.
+///
+/// A block left open,
+/// as in a truncated answer,
+/// runs to the end of the text.
+#[inline]
+fn code_blocks(text: &str, language: Option<&str>) -> Option<String> {
+    let mut blocks = String::new();
+    let mut found = false;
+    // The fence character and length of the current block,
+    // and whether it is kept.
+    let mut open: Option<(char, usize, bool)> = None;
+    for line in text.lines() {
+        let trimmed = line.trim_start();
+        match open {
+            // Blocks are closed by a fence at least as long as theirs.
+            Some((character, length, _))
+                if trimmed.trim_end().len() >= length
+                    && trimmed.trim_end().chars().all(|c| c == character) =>
+            {
+                open = None;
+            }
+            Some((_, _, keep)) => {
+                if keep {
+                    blocks.push_str(line);
+                    blocks.push('\n');
+                }
+            }
+            None => {
+                if let Some(fence) = FENCES.into_iter().find(|fence| trimmed.starts_with(fence)) {
+                    let character = fence.chars().next().unwrap_or('`');
+                    let info = trimmed.trim_start_matches(character);
+                    let length = trimmed.len() - info.len();
+                    let tag = info.split_whitespace().next().unwrap_or_default();
+                    let keep = language.is_none_or(|language| tag.eq_ignore_ascii_case(language));
+                    found |= keep;
+                    open = Some((character, length, keep));
+                }
+            }
+        }
+    }
+    found.then_some(blocks)
+}
+
+/// Get the first valid JSON object or array in a text.
+#[inline]
+fn first_json(text: &str) -> Option<&str> {
+    text.char_indices()
+        .filter(|&(_, c)| c == '{' || c == '[')
+        .find_map(|(start, _)| {
+            let mut values = Deserializer::from_str(&text[start..]).into_iter::<Value>();
+            match values.next() {
+                Some(Ok(_)) => Some(&text[start..start + values.byte_offset()]),
+                _ => None,
+            }
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parts_are_extracted() {
+        let answer = "Here you go:\n\n\
+                      ```bash\nls -S | head\n```\n\n\
+                      Or in Python:\n\n\
+                      ```python\nprint(sorted(files))\n```\n\n\
+                      The result is [not JSON] but {\"files\": [1, 2]}.\n";
+        let extract = |arg: &str| parse_extractor(arg).unwrap().extract(answer);
+
+        assert_eq!(
+            extract("code").unwrap(),
+            "ls -S | head\nprint(sorted(files))\n"
+        );
+        assert_eq!(extract("code:BASH").unwrap(), "ls -S | head\n");
+        assert_eq!(extract("json").unwrap(), "{\"files\": [1, 2]}\n");
+        assert_eq!(extract(r"regex:(\w+)\(").unwrap(), "print\nsorted\n");
+        assert_eq!(
+            code_blocks("````markdown\n```rust\nfn main() {}\n```\n`````\n", None).unwrap(),
+            "```rust\nfn main() {}\n```\n"
+        );
+        assert!(matches!(
+            extract("code:rust"),
+            Err(CliError::NothingExtracted(_))
+        ));
+        assert!(matches!(
+            parse_extractor("yaml"),
+            Err(CliError::UnknownExtractor(_))
+        ));
+    }
+}
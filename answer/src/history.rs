@@ -0,0 +1,379 @@
+//! A searchable history of invocations.
+//!
+//! Every answer is logged,
+//! along with the conversation it was given in,
+//! the parameters used,
+//! and how long it took,
+//! to a SQLite database in the user data directory.
+//! Questions and answers are indexed for full-text search.
+
+use std::fs;
+use std::path::PathBuf;
+use std::time::Duration;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+
+use async_openai::types::Usage;
+use clap::Subcommand;
+use rusqlite::params;
+use rusqlite::Connection;
+use rusqlite::OptionalExtension;
+use serde_json::json;
+use serde_json::Value;
+
+use crate::snippet;
+use crate::Bot;
+use crate::CliError;
+use crate::Conversation;
+
+/// Schema of the history database.
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS invocations (
+    id INTEGER PRIMARY KEY,
+    started_at INTEGER NOT NULL,
+    duration_ms INTEGER NOT NULL,
+    path TEXT,
+    conversation TEXT NOT NULL,
+    parameters TEXT NOT NULL,
+    reply TEXT NOT NULL,
+    usage TEXT
+);
+CREATE VIRTUAL TABLE IF NOT EXISTS invocations_fts USING fts5(question, reply);
+";
+
+/// Number of invocations listed when none is given.
+const DEFAULT_LIMIT: &str = "20";
+
+/// An invocation to log in the history.
+#[derive(Debug)]
+pub struct Invocation<'a> {
+    /// When the invocation started.
+    pub started: SystemTime,
+    /// How long the reply took.
+    pub duration: Duration,
+    /// The conversation file, if any.
+    pub path: Option<PathBuf>,
+    /// The [`Conversation`] sent, including the question.
+    pub conversation: &'a Conversation,
+    /// Parameters of the invocation.
+    pub parameters: Value,
+    /// The reply.
+    pub reply: &'a str,
+    /// Token usage reported for the request, if any.
+    pub usage: Option<Usage>,
+}
+
+/// The history database.
+#[derive(Debug)]
+pub struct History {
+    /// Connection to the database.
+    connection: Connection,
+}
+
+impl History {
+    /// Open the [`History`] in the user data directory,
+    /// creating it as needed.
+    #[inline]
+    pub fn open() -> Result<Self, CliError> {
+        let path = path().ok_or(CliError::NoDataDir)?;
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        Self::with_connection(Connection::open(path)?)
+    }
+
+    /// Create a [`History`] over a [`Connection`],
+    /// creating tables as needed.
+    #[inline]
+    fn with_connection(connection: Connection) -> Result<Self, CliError> {
+        connection.execute_batch(SCHEMA)?;
+        Ok(Self { connection })
+    }
+
+    /// Log an [`Invocation`].
+    ///
+    /// Returns the identifier of the logged invocation.
+    #[inline]
+    pub fn log(&mut self, invocation: &Invocation<'_>) -> Result<i64, CliError> {
+        let question = invocation
+            .conversation
+            .messages
+            .last()
+            .filter(|message| crate::is_user(&message.role))
+            .map(|message| message.content.to_string())
+            .unwrap_or_default();
+
+        let transaction = self.connection.transaction()?;
+        transaction.execute(
+            "INSERT INTO invocations \
+             (started_at, duration_ms, path, conversation, parameters, reply, usage) \
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+            params![
+                invocation
+                    .started
+                    .duration_since(UNIX_EPOCH)
+                    .map_or(0, |since| i64::try_from(since.as_secs())
+                        .unwrap_or(i64::MAX)),
+                i64::try_from(invocation.duration.as_millis()).unwrap_or(i64::MAX),
+                invocation
+                    .path
+                    .as_ref()
+                    .map(|path| path.to_string_lossy().into_owned()),
+                serde_yaml::to_string(invocation.conversation)?,
+                invocation.parameters.to_string(),
+                invocation.reply,
+                invocation
+                    .usage
+                    .as_ref()
+                    .map(serde_json::to_string)
+                    .transpose()?,
+            ],
+        )?;
+        let id = transaction.last_insert_rowid();
+        transaction.execute(
+            "INSERT INTO invocations_fts (rowid, question, reply) VALUES (?1, ?2, ?3)",
+            params![id, question, invocation.reply],
+        )?;
+        transaction.commit()?;
+        Ok(id)
+    }
+
+    /// List the most recent invocations,
+    /// optionally matching a full-text search query.
+    ///
+    /// Returns their identifiers, start times and questions.
+    #[inline]
+    fn list(
+        &self,
+        query: Option<&str>,
+        limit: usize,
+    ) -> Result<Vec<(i64, String, String)>, CliError> {
+        let limit = i64::try_from(limit).unwrap_or(i64::MAX);
+        let row = |row: &rusqlite::Row<'_>| Ok((row.get(0)?, row.get(1)?, row.get(2)?));
+        let invocations = match query {
+            Some(query) => self
+                .connection
+                .prepare(
+                    "SELECT i.id, datetime(i.started_at, 'unixepoch', 'localtime'), f.question \
+                     FROM invocations_fts f JOIN invocations i ON i.id = f.rowid \
+                     WHERE invocations_fts MATCH ?1 ORDER BY rank LIMIT ?2",
+                )?
+                .query_map(params![phrase(query), limit], row)?
+                .collect::<Result<_, _>>()?,
+            None => self
+                .connection
+                .prepare(
+                    "SELECT i.id, datetime(i.started_at, 'unixepoch', 'localtime'), f.question \
+                     FROM invocations i JOIN invocations_fts f ON f.rowid = i.id \
+                     ORDER BY i.id DESC LIMIT ?1",
+                )?
+                .query_map(params![limit], row)?
+                .collect::<Result<_, _>>()?,
+        };
+        Ok(invocations)
+    }
+
+    /// Get a logged invocation,
+    /// formatted for display.
+    #[inline]
+    fn show(&self, id: i64) -> Result<String, CliError> {
+        self.connection
+            .query_row(
+                "SELECT datetime(started_at, 'unixepoch', 'localtime'), duration_ms, path, \
+                 parameters, usage, conversation, reply FROM invocations WHERE id = ?1",
+                params![id],
+                |row| {
+                    let started: String = row.get(0)?;
+                    let duration: i64 = row.get(1)?;
+                    let path: Option<String> = row.get(2)?;
+                    let parameters: String = row.get(3)?;
+                    let usage: Option<String> = row.get(4)?;
+                    let conversation: String = row.get(5)?;
+                    let reply: String = row.get(6)?;
+                    Ok(format!(
+                        "id: {id}\n\
+                         started: {started}\n\
+                         duration: {duration}ms\n\
+                         path: {path}\n\
+                         parameters: {parameters}\n\
+                         usage: {usage}\n\
+                         \n{conversation}\n{reply}\n",
+                        path = path.as_deref().unwrap_or("-"),
+                        usage = usage.as_deref().unwrap_or("-"),
+                    ))
+                },
+            )
+            .optional()?
+            .ok_or(CliError::InvocationNotFound(id))
+    }
+
+    /// Get the [`Conversation`] and parameters of a logged invocation.
+    #[inline]
+    fn conversation(&self, id: i64) -> Result<(Conversation, Value), CliError> {
+        let (conversation, parameters): (String, String) = self
+            .connection
+            .query_row(
+                "SELECT conversation, parameters FROM invocations WHERE id = ?1",
+                params![id],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .optional()?
+            .ok_or(CliError::InvocationNotFound(id))?;
+        Ok((
+            Conversation::from_reader(conversation.as_bytes())?,
+            serde_json::from_str(&parameters)?,
+        ))
+    }
+}
+
+/// Quote a search query as an FTS5 phrase,
+/// so that its punctuation is not taken for query syntax.
+#[inline]
+fn phrase(query: &str) -> String {
+    format!("\"{query}\"", query = query.replace('"', "\"\""))
+}
+
+/// Get the path of the history database.
+#[inline]
+fn path() -> Option<PathBuf> {
+    dirs::data_dir().map(|directory| directory.join("answer").join("history.sqlite3"))
+}
+
+/// Browse the history of invocations.
+#[derive(Debug, Subcommand)]
+pub enum HistoryCommand {
+    /// List the most recent invocations.
+    List {
+        /// Maximum number of invocations to list.
+        #[arg(short = 'n', long, default_value = DEFAULT_LIMIT)]
+        limit: usize,
+    },
+    /// Search questions and answers.
+    Search {
+        /// Full-text search query.
+        query: String,
+
+        /// Maximum number of invocations to list.
+        #[arg(short = 'n', long, default_value = DEFAULT_LIMIT)]
+        limit: usize,
+    },
+    /// Show an invocation in full.
+    Show {
+        /// Identifier of the invocation.
+        id: i64,
+    },
+    /// Send the conversation of an invocation again.
+    Rerun {
+        /// Identifier of the invocation.
+        id: i64,
+    },
+}
+
+impl HistoryCommand {
+    /// Run this [`HistoryCommand`].
+    #[inline]
+    pub async fn run(self, bot: &Bot) -> Result<(), CliError> {
+        let history = History::open()?;
+        match self {
+            Self::List { limit } => print_list(&history.list(None, limit)?),
+            Self::Search { query, limit } => print_list(&history.list(Some(&query), limit)?),
+            Self::Show { id } => print!("{}", history.show(id)?),
+            Self::Rerun { id } => {
+                let (conversation, mut parameters) = history.conversation(id)?;
+                // The logged parameters apply unless overridden.
+                let bot = Bot {
+                    max_tokens: bot.max_tokens.or_else(|| {
+                        parameters["max_tokens"]
+                            .as_u64()
+                            .and_then(|tokens| u16::try_from(tokens).ok())
+                    }),
+                    ..bot.clone()
+                };
+                let started = SystemTime::now();
+                let reply = bot.reply(&conversation, tokio::io::stdout()).await?;
+                println!();
+                if let Value::Object(parameters) = &mut parameters {
+                    parameters.insert("max_tokens".to_owned(), json!(bot.max_tokens));
+                    parameters.insert("rerun".to_owned(), json!(id));
+                }
+                bot.log(&Invocation {
+                    started,
+                    duration: started.elapsed().unwrap_or_default(),
+                    path: None,
+                    conversation: &conversation,
+                    parameters,
+                    reply: &reply.text,
+                    usage: reply.usage,
+                });
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Print a list of invocations.
+#[inline]
+fn print_list(invocations: &[(i64, String, String)]) {
+    for (id, started, question) in invocations {
+        println!(
+            "{id:>5}  {started}  {question}",
+            question = snippet(question)
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Message;
+
+    #[test]
+    fn invocations_are_searchable() {
+        let mut history = History::with_connection(Connection::open_in_memory().unwrap()).unwrap();
+        for (question, reply) in [
+            ("Malcolm X", "May 19th, 1925."),
+            ("Martin Luther King Jr.", "January 15th, 1929."),
+        ] {
+            let mut conversation = Conversation::default();
+            conversation.push(Message::from_user(question));
+            history
+                .log(&Invocation {
+                    started: SystemTime::now(),
+                    duration: Duration::from_millis(42),
+                    path: None,
+                    conversation: &conversation,
+                    parameters: Value::Null,
+                    reply,
+                    usage: Some(Usage {
+                        prompt_tokens: 12,
+                        completion_tokens: 8,
+                        total_tokens: 20,
+                    }),
+                })
+                .unwrap();
+        }
+
+        let recent = history.list(None, 1).unwrap();
+        assert_eq!(recent.len(), 1);
+        assert_eq!(recent[0].2, "Martin Luther King Jr.");
+
+        let found = history.list(Some("1925"), 10).unwrap();
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].2, "Malcolm X");
+        assert_eq!(history.list(Some("Malcolm-X"), 10).unwrap().len(), 1);
+        assert!(history
+            .list(Some("\"King Jr.\" OR"), 10)
+            .unwrap()
+            .is_empty());
+
+        let (conversation, _) = history.conversation(found[0].0).unwrap();
+        assert_eq!(conversation.messages[0].content.to_string(), "Malcolm X");
+        let shown = history.show(found[0].0).unwrap();
+        assert!(shown.contains("duration: 42ms"));
+        assert!(shown.contains("\"total_tokens\":20"));
+        assert!(matches!(
+            history.show(3),
+            Err(CliError::InvocationNotFound(3))
+        ));
+    }
+}
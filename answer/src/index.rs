@@ -0,0 +1,311 @@
+//! Retrieval of relevant excerpts from a local directory.
+//!
+//! Text files in the directory are split into chunks of consecutive lines,
+//! which are embedded and cached in the user cache directory.
+//! The cache is updated incrementally:
+//! files whose modification time or contents did not change are not embedded
+//! again.
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::fs;
+use std::fs::File;
+use std::io::BufWriter;
+use std::io::{self};
+use std::path::Path;
+use std::path::PathBuf;
+use std::time::SystemTime;
+
+use serde::Deserialize;
+use serde::Serialize;
+use sha2::Digest;
+use sha2::Sha256;
+
+use crate::embed;
+use crate::embed::Entry;
+use crate::embed::DEFAULT_MODEL;
+use crate::Bot;
+use crate::CliError;
+use crate::Conversation;
+use crate::Message;
+
+/// Maximum number of characters in a chunk.
+const CHUNK_CHARS: usize = 1500;
+
+/// Number of chunks retrieved for a question.
+const RETRIEVED_CHUNKS: usize = 4;
+
+/// An embedded directory.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Index {
+    /// The model that computed the vectors in this [`Index`].
+    model: String,
+    /// Indexed files, by path.
+    #[serde(default)]
+    files: BTreeMap<PathBuf, IndexedFile>,
+}
+
+/// A file in an [`Index`].
+#[derive(Debug, Serialize, Deserialize)]
+struct IndexedFile {
+    /// Modification time of the file when it was indexed.
+    modified: SystemTime,
+    /// SHA-256 hash of the contents of the file when it was indexed.
+    hash: String,
+    /// Embedded chunks of the file.
+    chunks: Vec<Entry>,
+}
+
+impl Index {
+    /// Load the cached [`Index`] of a directory,
+    /// embedding new and changed files.
+    #[inline]
+    async fn update(bot: &Bot, directory: &Path) -> Result<Self, CliError> {
+        let cache = cache_path(directory)?;
+        let mut index: Self = match &cache {
+            Some(cache) => match fs::read(cache) {
+                Ok(bytes) => serde_json::from_slice(&bytes).unwrap_or_else(|err| {
+                    log::warn!("ignoring corrupted index cache: {err}");
+                    Self::default()
+                }),
+                Err(_) => Self::default(),
+            },
+            None => Self::default(),
+        };
+        if index.model != DEFAULT_MODEL {
+            index = Self {
+                model: DEFAULT_MODEL.to_owned(),
+                files: BTreeMap::new(),
+            };
+        }
+
+        let mut files = BTreeMap::new();
+        let mut pending = Vec::new();
+        for path in text_files(directory)? {
+            let modified = fs::metadata(&path)?.modified()?;
+            let previous = match index.files.remove(&path) {
+                Some(file) if file.modified == modified => {
+                    files.insert(path, file);
+                    continue;
+                }
+                previous => previous,
+            };
+
+            let Ok(contents) = fs::read_to_string(&path) else {
+                log::debug!("skipping non-text file {path:?}");
+                continue;
+            };
+            let hash = hash(&contents);
+            match previous {
+                Some(mut file) if file.hash == hash => {
+                    file.modified = modified;
+                    files.insert(path, file);
+                }
+                _ => pending.push((path, modified, hash, contents)),
+            }
+        }
+
+        // Whatever is left in the index was removed from the directory.
+        let changed = !pending.is_empty() || !index.files.is_empty();
+        log::debug!(
+            "embedding {changed} changed files, dropping {removed} removed files",
+            changed = pending.len(),
+            removed = index.files.len()
+        );
+
+        let chunks: Vec<_> = pending
+            .iter()
+            .map(|(path, _, _, contents)| chunk(path, contents))
+            .collect();
+        let mut vectors = embed::embed(
+            bot,
+            &index.model,
+            chunks
+                .iter()
+                .flatten()
+                .map(|(_, text)| text.clone())
+                .collect(),
+        )
+        .await?
+        .into_iter();
+
+        for ((path, modified, hash, _), chunks) in pending.into_iter().zip(chunks) {
+            let chunks = chunks
+                .into_iter()
+                .zip(vectors.by_ref())
+                .map(|((source, text), vector)| Entry {
+                    source,
+                    text,
+                    vector,
+                })
+                .collect();
+            files.insert(
+                path,
+                IndexedFile {
+                    modified,
+                    hash,
+                    chunks,
+                },
+            );
+        }
+        index.files = files;
+
+        if let (true, Some(cache)) = (changed, &cache) {
+            if let Some(parent) = cache.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            // The cache is replaced atomically,
+            // so an interruption never leaves it truncated.
+            let temporary = cache.with_extension("tmp");
+            let mut writer = BufWriter::new(File::create(&temporary)?);
+            serde_json::to_writer(&mut writer, &index)?;
+            writer
+                .into_inner()
+                .map_err(io::IntoInnerError::into_error)?
+                .sync_all()?;
+            fs::rename(temporary, cache)?;
+        }
+
+        Ok(index)
+    }
+}
+
+/// Add the excerpts of a directory most relevant to a question to a
+/// [`Conversation`], as a system message.
+///
+/// Returns the sources of the excerpts,
+/// in the order they are numbered in the message.
+#[inline]
+pub async fn augment(
+    bot: &Bot,
+    directory: &Path,
+    conversation: &mut Conversation,
+    question: &str,
+) -> Result<Vec<String>, CliError> {
+    let index = Index::update(bot, directory).await?;
+
+    let query = embed::embed(bot, &index.model, vec![question.to_owned()])
+        .await?
+        .pop()
+        .unwrap_or_default();
+    let excerpts = embed::nearest(
+        &query,
+        index.files.values().flat_map(|file| &file.chunks),
+        RETRIEVED_CHUNKS,
+    );
+    if excerpts.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let mut content = String::from(
+        "Use the following numbered excerpts to answer the next question \
+         and cite them by number (e.g., [1]) where relevant.\n",
+    );
+    for (number, (_, entry)) in excerpts.iter().enumerate() {
+        write!(
+            content,
+            "\n[{number}] {source}\n{text}\n",
+            number = number + 1,
+            source = entry.source,
+            text = entry.text
+        )
+        .expect("writing to a string should not fail");
+    }
+    conversation.push(Message::from_system(content));
+
+    Ok(excerpts
+        .into_iter()
+        .map(|(_, entry)| entry.source.clone())
+        .collect())
+}
+
+/// Where the [`Index`] of a directory is cached, if anywhere.
+#[inline]
+fn cache_path(directory: &Path) -> Result<Option<PathBuf>, CliError> {
+    let directory = fs::canonicalize(directory)?;
+    Ok(dirs::cache_dir().map(|cache| {
+        let key = hash(&directory.to_string_lossy());
+        cache
+            .join("answer")
+            .join("indices")
+            .join(format!("{key}.json", key = &key[..16]))
+    }))
+}
+
+/// Recursively list the files of a directory, skipping hidden entries.
+#[inline]
+fn text_files(directory: &Path) -> Result<Vec<PathBuf>, CliError> {
+    let mut files = Vec::new();
+    let mut directories = vec![directory.to_path_buf()];
+    while let Some(directory) = directories.pop() {
+        for entry in fs::read_dir(directory)? {
+            let entry = entry?;
+            if entry.file_name().to_string_lossy().starts_with('.') {
+                continue;
+            }
+
+            let file_type = entry.file_type()?;
+            if file_type.is_dir() {
+                directories.push(entry.path());
+            } else if file_type.is_file() {
+                files.push(entry.path());
+            }
+        }
+    }
+    Ok(files)
+}
+
+/// Split the contents of a file into chunks of consecutive lines.
+///
+/// Each chunk comes with its source,
+/// in the form `path:first-last` (line numbers start at one).
+#[inline]
+fn chunk(path: &Path, contents: &str) -> Vec<(String, String)> {
+    let mut chunks = Vec::new();
+    let mut text = String::new();
+    let mut first = 1;
+
+    for (index, line) in contents.lines().enumerate() {
+        if !text.is_empty() && text.len() + line.len() > CHUNK_CHARS {
+            chunks.push((
+                format!("{path}:{first}-{index}", path = path.display()),
+                text,
+            ));
+            text = String::new();
+            first = index + 1;
+        }
+        text.push_str(line);
+        text.push('\n');
+    }
+    if !text.trim().is_empty() {
+        let last = contents.lines().count();
+        chunks.push((
+            format!("{path}:{first}-{last}", path = path.display()),
+            text,
+        ));
+    }
+
+    chunks.retain(|(_, text)| !text.trim().is_empty());
+    chunks
+}
+
+/// Compute the hexadecimal SHA-256 hash of a text.
+#[inline]
+fn hash(text: &str) -> String {
+    format!("{hash:x}", hash = Sha256::digest(text))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn chunks_keep_line_ranges() {
+        let line = "a".repeat(CHUNK_CHARS / 3);
+        let contents = format!("{line}\n{line}\n\n{line}\n");
+
+        let chunks = chunk(Path::new("doc.txt"), &contents);
+        let sources: Vec<_> = chunks.iter().map(|(source, _)| source.as_str()).collect();
+        assert_eq!(sources, ["doc.txt:1-3", "doc.txt:4-4"]);
+    }
+}
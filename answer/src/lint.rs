@@ -0,0 +1,666 @@
+//! Linting of conversation files.
+//!
+//! Conversation files are checked for mistakes that would otherwise go
+//! unnoticed or only surface once sent:
+//!
+//! - invalid YAML, or values of the wrong type,
+//! - unknown keys (denied with `--strict`),
+//! - misspelled roles,
+//! - messages with empty content,
+//! - system or developer messages after the start of the conversation,
+//! - consecutive messages with the same role,
+//! - unknown parents in branching conversations.
+//!
+//! Misspelled roles and keys are fixed in place with `--fix`.
+//! Positions are those of block-style YAML,
+//! as written by `answer --save`.
+
+use std::fmt;
+use std::fs;
+use std::fs::File;
+use std::io::Write;
+use std::path::Path;
+use std::path::PathBuf;
+
+use clap::Args;
+use console::style;
+use serde_yaml::Mapping;
+use serde_yaml::Value;
+
+use crate::compaction;
+use crate::CliError;
+use crate::Conversation;
+use crate::Role;
+
+/// Keys of a [`Conversation`].
+const CONVERSATION_KEYS: &[&str] = &["compaction", "mcp", "messages", "steps"];
+
+/// Keys of a [`Message`](crate::Message).
+const MESSAGE_KEYS: &[&str] = &[
+    "role",
+    "content",
+    "name",
+    "id",
+    "parent",
+    "tool_calls",
+    "tool_call_id",
+    "options",
+];
+
+/// Keys of a [`Compaction`](crate::compaction::Compaction).
+const COMPACTION_KEYS: &[&str] = &["threshold", "keep", "prompt", "preserve"];
+
+/// Keys of an MCP [`Server`](crate::mcp::Server).
+const SERVER_KEYS: &[&str] = &["name", "command", "args", "env", "url"];
+
+/// Roles of a [`Message`](crate::Message).
+const ROLES: &[&str] = &["system", "developer", "user", "assistant", "tool"];
+
+/// Check conversation files for mistakes.
+#[derive(Debug, Args)]
+pub struct LintCommand {
+    /// Paths to conversation YAML files.
+    #[arg(required = true)]
+    files: Vec<PathBuf>,
+
+    /// Deny unknown keys instead of warning about them.
+    #[arg(long)]
+    strict: bool,
+
+    /// Fix misspelled roles and keys in place.
+    #[arg(long)]
+    fix: bool,
+}
+
+impl LintCommand {
+    /// Run this [`LintCommand`].
+    #[inline]
+    pub fn run(self) -> Result<(), CliError> {
+        let mut errors = 0;
+        for path in &self.files {
+            let mut source = fs::read_to_string(path)?;
+            let mut diagnostics = lint(&source, self.strict);
+
+            if self.fix {
+                let (fixed_source, fixed) = apply(&source, &diagnostics);
+                if fixed > 0 {
+                    source = fixed_source;
+                    replace(path, &source)?;
+                    println!(
+                        "{} {fixed} problems in {path}",
+                        style("fixed").green(),
+                        path = path.display()
+                    );
+                    diagnostics = lint(&source, self.strict);
+                }
+            }
+
+            for diagnostic in &diagnostics {
+                println!("{path}:{diagnostic}", path = path.display());
+            }
+            errors += diagnostics
+                .iter()
+                .filter(|diagnostic| diagnostic.severity == Severity::Error)
+                .count();
+        }
+
+        if errors > 0 {
+            return Err(CliError::LintFailed(errors));
+        }
+        Ok(())
+    }
+}
+
+/// How serious a [`Diagnostic`] is.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Severity {
+    /// The file is likely to work, but not as intended.
+    Warning,
+    /// The file cannot be used.
+    Error,
+}
+
+/// A problem found in a conversation file.
+#[derive(Debug)]
+struct Diagnostic {
+    /// Line of the problem, starting at one.
+    line: usize,
+    /// Column of the problem, starting at one.
+    column: usize,
+    /// How serious the problem is.
+    severity: Severity,
+    /// Description of the problem.
+    message: String,
+    /// Text to replace on the line to fix the problem, if trivial.
+    fix: Option<(String, String)>,
+}
+
+impl fmt::Display for Diagnostic {
+    #[inline]
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let severity = match self.severity {
+            Severity::Warning => style("warning").yellow(),
+            Severity::Error => style("error").red(),
+        };
+        write!(
+            f,
+            "{line}:{column}: {severity}: {message}",
+            line = self.line,
+            column = self.column,
+            message = self.message
+        )?;
+        if let Some((_, to)) = &self.fix {
+            write!(f, " (fixable: {to:?})")?;
+        }
+        Ok(())
+    }
+}
+
+/// Lint the source of a conversation file.
+#[inline]
+fn lint(source: &str, strict: bool) -> Vec<Diagnostic> {
+    let locator = Locator::new(source);
+    let mut diagnostics = Vec::new();
+
+    let value: Value = match serde_yaml::from_str(source) {
+        Ok(value) => value,
+        Err(err) => {
+            diagnostics.push(parse_error(&err));
+            return diagnostics;
+        }
+    };
+    let unknown = if strict {
+        Severity::Error
+    } else {
+        Severity::Warning
+    };
+    match &value {
+        Value::Null => {}
+        Value::Mapping(conversation) => {
+            check_keys(
+                conversation,
+                CONVERSATION_KEYS,
+                |key| locator.top(key),
+                unknown,
+                &mut diagnostics,
+            );
+            if let Some(Value::Mapping(compaction)) = conversation.get("compaction") {
+                check_keys(
+                    compaction,
+                    COMPACTION_KEYS,
+                    |key| locator.nested("compaction", key),
+                    unknown,
+                    &mut diagnostics,
+                );
+            }
+            if let Some(Value::Sequence(servers)) = conversation.get("mcp") {
+                for (index, server) in servers.iter().enumerate() {
+                    if let Value::Mapping(server) = server {
+                        check_keys(
+                            server,
+                            SERVER_KEYS,
+                            |key| locator.item("mcp", index, Some(key)),
+                            unknown,
+                            &mut diagnostics,
+                        );
+                    }
+                }
+            }
+            if let Some(Value::Sequence(messages)) = conversation.get("messages") {
+                for (index, message) in messages.iter().enumerate() {
+                    if let Value::Mapping(message) = message {
+                        check_keys(
+                            message,
+                            MESSAGE_KEYS,
+                            |key| locator.item("messages", index, Some(key)),
+                            unknown,
+                            &mut diagnostics,
+                        );
+                        check_role(message, index, &locator, &mut diagnostics);
+                    }
+                }
+            }
+        }
+        _ => diagnostics.push(Diagnostic {
+            line: 1,
+            column: 1,
+            severity: Severity::Error,
+            message: "conversation is not a mapping".to_owned(),
+            fix: None,
+        }),
+    }
+    if diagnostics
+        .iter()
+        .any(|diagnostic| diagnostic.severity == Severity::Error)
+    {
+        return diagnostics;
+    }
+
+    match Conversation::from_reader(source.as_bytes()) {
+        Ok(conversation) => check_messages(&conversation, &locator, &mut diagnostics),
+        Err(err) => diagnostics.push(parse_error(&err)),
+    }
+    diagnostics
+}
+
+/// Turn a YAML error into a [`Diagnostic`].
+#[inline]
+fn parse_error(err: &serde_yaml::Error) -> Diagnostic {
+    let (line, column) = err
+        .location()
+        .map_or((1, 1), |location| (location.line(), location.column()));
+    // Locations are already in the message.
+    let message = err.to_string();
+    let message = message
+        .split_once(" at line ")
+        .map_or(message.as_str(), |(message, _)| message)
+        .to_owned();
+    Diagnostic {
+        line,
+        column,
+        severity: Severity::Error,
+        message,
+        fix: None,
+    }
+}
+
+/// Check that the keys of a [`Mapping`] are known,
+/// suggesting known keys for misspelled ones.
+#[inline]
+fn check_keys<F>(
+    mapping: &Mapping,
+    known: &[&str],
+    locate: F,
+    severity: Severity,
+    diagnostics: &mut Vec<Diagnostic>,
+) where
+    F: Fn(&str) -> Option<(usize, usize)>,
+{
+    for key in mapping.keys() {
+        let Some(key) = key.as_str() else {
+            continue;
+        };
+        if known.contains(&key) {
+            continue;
+        }
+        let (line, column) = locate(key).unwrap_or((1, 1));
+        let suggestion = closest(key, known).filter(|known| !mapping.contains_key(*known));
+        diagnostics.push(Diagnostic {
+            line,
+            column,
+            severity,
+            message: match suggestion {
+                Some(suggestion) => format!("unknown key {key:?}, did you mean {suggestion:?}?"),
+                None => format!("unknown key {key:?}"),
+            },
+            fix: suggestion.map(|suggestion| (format!("{key}:"), format!("{suggestion}:"))),
+        });
+    }
+}
+
+/// Check that the role of a message is known.
+#[inline]
+fn check_role(
+    message: &Mapping,
+    index: usize,
+    locator: &Locator<'_>,
+    diagnostics: &mut Vec<Diagnostic>,
+) {
+    let Some(role) = message.get("role") else {
+        return;
+    };
+    let (line, column) = locator
+        .item("messages", index, Some("role"))
+        .unwrap_or((1, 1));
+    let Some(role) = role.as_str() else {
+        diagnostics.push(Diagnostic {
+            line,
+            column,
+            severity: Severity::Error,
+            message: "role is not a string".to_owned(),
+            fix: None,
+        });
+        return;
+    };
+    if ROLES.contains(&role) {
+        return;
+    }
+
+    let suggestion = closest(role, ROLES);
+    diagnostics.push(Diagnostic {
+        line,
+        column,
+        severity: Severity::Error,
+        message: match suggestion {
+            Some(suggestion) => format!("unknown role {role:?}, did you mean {suggestion:?}?"),
+            None => format!(
+                "unknown role {role:?}, expected one of {roles}",
+                roles = ROLES.join(", ")
+            ),
+        },
+        fix: suggestion.map(|suggestion| (format!("role: {role}"), format!("role: {suggestion}"))),
+    });
+}
+
+/// Check the [`Message`](crate::Message)s of a [`Conversation`].
+#[inline]
+fn check_messages(
+    conversation: &Conversation,
+    locator: &Locator<'_>,
+    diagnostics: &mut Vec<Diagnostic>,
+) {
+    let mut warn = |index: usize, key: Option<&str>, message: String| {
+        let (line, column) = locator
+            .item("messages", index, key)
+            .or_else(|| locator.item("messages", index, None))
+            .unwrap_or((1, 1));
+        diagnostics.push(Diagnostic {
+            line,
+            column,
+            severity: Severity::Warning,
+            message,
+            fix: None,
+        });
+    };
+
+    let mut started = false;
+    for (index, message) in conversation.messages.iter().enumerate() {
+        let id = conversation.id(index);
+        // Assistant messages calling tools need no content.
+        if message.content.is_empty() && message.tool_calls.is_empty() {
+            warn(index, None, format!("message {id} has empty content"));
+        }
+
+        if matches!(message.role, Role::System | Role::Developer) && !compaction::is_memory(message)
+        {
+            if started {
+                warn(
+                    index,
+                    Some("role"),
+                    format!(
+                        "{role} message {id} is not at the start of the conversation",
+                        role = message.role
+                    ),
+                );
+            }
+        } else {
+            started = true;
+        }
+
+        let parent = match &message.parent {
+            Some(parent) => {
+                let found = conversation.find(parent);
+                if found.is_none() {
+                    warn(
+                        index,
+                        Some("parent"),
+                        format!("message {id} follows unknown message {parent:?}"),
+                    );
+                }
+                found
+            }
+            None => index.checked_sub(1),
+        };
+        if let Some(parent) = parent {
+            if matches!(message.role, Role::User | Role::Assistant)
+                && conversation.messages[parent].role == message.role
+            {
+                warn(
+                    index,
+                    Some("role"),
+                    format!(
+                        "message {id} has the same role as message {parent} it follows",
+                        parent = conversation.id(parent)
+                    ),
+                );
+            }
+        }
+    }
+}
+
+/// Find the closest known word to a misspelled one, if close enough.
+#[inline]
+fn closest<'a>(word: &str, known: &[&'a str]) -> Option<&'a str> {
+    known
+        .iter()
+        .map(|known| (distance(&word.to_lowercase(), known), *known))
+        .filter(|&(distance, _)| distance <= 2)
+        .min_by_key(|&(distance, _)| distance)
+        .map(|(_, known)| known)
+}
+
+/// Compute the Levenshtein distance between two words.
+#[inline]
+fn distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    for (i, a) in a.chars().enumerate() {
+        let mut current = vec![i + 1];
+        for (j, b) in b.iter().enumerate() {
+            let substitution = previous[j] + usize::from(a != *b);
+            current.push(substitution.min(previous[j + 1] + 1).min(current[j] + 1));
+        }
+        previous = current;
+    }
+    previous[b.len()]
+}
+
+/// Replace a file with a source.
+///
+/// The file is replaced atomically,
+/// so an interruption never leaves a truncated file behind.
+#[inline]
+fn replace(path: &Path, source: &str) -> Result<(), CliError> {
+    let mut temporary = path.as_os_str().to_owned();
+    temporary.push(".tmp");
+    let mut file = File::create(&temporary)?;
+    file.write_all(source.as_bytes())?;
+    file.sync_all()?;
+    file.set_permissions(fs::metadata(path)?.permissions())?;
+    fs::rename(temporary, path)?;
+    Ok(())
+}
+
+/// Apply the fixes of [`Diagnostic`]s to a source.
+///
+/// Returns the fixed source,
+/// and how many fixes were applied.
+#[inline]
+fn apply(source: &str, diagnostics: &[Diagnostic]) -> (String, usize) {
+    let mut fixed = 0;
+    let mut lines: Vec<String> = source.lines().map(str::to_owned).collect();
+    for diagnostic in diagnostics {
+        let Some((from, to)) = &diagnostic.fix else {
+            continue;
+        };
+        let Some(line) = lines.get_mut(diagnostic.line - 1) else {
+            continue;
+        };
+        let start = line
+            .char_indices()
+            .nth(diagnostic.column - 1)
+            .map_or(line.len(), |(start, _)| start);
+        if let Some(offset) = line[start..].find(from.as_str()) {
+            line.replace_range(start + offset..start + offset + from.len(), to);
+            fixed += 1;
+        }
+    }
+    let mut fixed_source = lines.join("\n");
+    if source.ends_with('\n') {
+        fixed_source.push('\n');
+    }
+    (fixed_source, fixed)
+}
+
+/// Locates keys and items in the source of a block-style YAML file.
+#[derive(Debug)]
+struct Locator<'a> {
+    /// Lines of the source.
+    lines: Vec<&'a str>,
+}
+
+impl<'a> Locator<'a> {
+    /// Create a [`Locator`] over a source.
+    #[inline]
+    fn new(source: &'a str) -> Self {
+        Self {
+            lines: source.lines().collect(),
+        }
+    }
+
+    /// Locate a top-level key.
+    #[inline]
+    fn top(&self, key: &str) -> Option<(usize, usize)> {
+        self.lines
+            .iter()
+            .position(|line| is_key(line, key))
+            .map(|line| (line + 1, 1))
+    }
+
+    /// Get the range of lines in the block of a top-level key,
+    /// excluding the key itself.
+    #[inline]
+    fn block(&self, key: &str) -> Option<(usize, usize)> {
+        let (start, _) = self.top(key)?;
+        let end = (start..self.lines.len())
+            .find(|&line| {
+                let text = self.lines[line];
+                indent(text) == 0 && !is_blank(text) && !text.starts_with('-')
+            })
+            .unwrap_or(self.lines.len());
+        Some((start, end))
+    }
+
+    /// Locate a key nested in the block of a top-level key.
+    #[inline]
+    fn nested(&self, top: &str, key: &str) -> Option<(usize, usize)> {
+        let (start, end) = self.block(top)?;
+        (start..end)
+            .find(|&line| is_key(self.lines[line].trim_start(), key))
+            .map(|line| (line + 1, indent(self.lines[line]) + 1))
+    }
+
+    /// Locate an item in the sequence of a top-level key,
+    /// or one of its keys.
+    #[inline]
+    fn item(&self, top: &str, index: usize, key: Option<&str>) -> Option<(usize, usize)> {
+        let (start, end) = self.block(top)?;
+        let first = (start..end).find(|&line| !is_blank(self.lines[line]))?;
+        let dash = indent(self.lines[first]);
+        let items: Vec<usize> = (first..end)
+            .filter(|&line| {
+                let text = self.lines[line];
+                indent(text) == dash && text.trim_start().starts_with('-')
+            })
+            .collect();
+        let item = *items.get(index)?;
+        let Some(key) = key else {
+            return Some((item + 1, dash + 1));
+        };
+
+        let after =
+            self.lines[item][dash + 1..].len() - self.lines[item][dash + 1..].trim_start().len();
+        let column = dash + 1 + after;
+        let next = items.get(index + 1).copied().unwrap_or(end);
+        (item..next)
+            .find(|&line| {
+                let text = self.lines[line];
+                text.get(column..).is_some_and(|rest| is_key(rest, key))
+                    && (line == item || indent(text) == column)
+            })
+            .map(|line| (line + 1, column + 1))
+    }
+}
+
+/// Determine whether a line starts with a key.
+#[inline]
+fn is_key(line: &str, key: &str) -> bool {
+    line.strip_prefix(key)
+        .is_some_and(|rest| rest.starts_with(':'))
+}
+
+/// Get the indentation of a line.
+#[inline]
+fn indent(line: &str) -> usize {
+    line.len() - line.trim_start().len()
+}
+
+/// Determine whether a line is blank or a comment.
+#[inline]
+fn is_blank(line: &str) -> bool {
+    let line = line.trim_start();
+    line.is_empty() || line.starts_with('#')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mistakes_are_found_and_fixed() {
+        let source = "\
+messages:
+  - role: system
+    content: You are a date of birth checker.
+  - content: Malcolm X
+    nmae: Alice
+  - role: asistant
+    content: May 19th, 1925.
+  - role: assistant
+    content: ''
+  - role: system
+    content: Be brief.
+";
+        let diagnostics = lint(source, false);
+        let found: Vec<_> = diagnostics
+            .iter()
+            .map(|diagnostic| (diagnostic.line, diagnostic.column, diagnostic.severity))
+            .collect();
+        assert_eq!(found, [(5, 5, Severity::Warning), (6, 5, Severity::Error)]);
+
+        let (fixed, count) = apply(source, &diagnostics);
+        assert_eq!(count, 2);
+        let missing = Diagnostic {
+            line: 1,
+            column: 1,
+            severity: Severity::Error,
+            message: String::new(),
+            fix: Some(("asistant".to_owned(), "assistant".to_owned())),
+        };
+        assert_eq!(apply("role: user\n", &[missing]).1, 0);
+        assert!(fixed.contains("    name: Alice\n  - role: assistant\n"));
+        let diagnostics = lint(&fixed, true);
+        let found: Vec<_> = diagnostics
+            .iter()
+            .map(|diagnostic| (diagnostic.line, diagnostic.message.as_str()))
+            .collect();
+        assert_eq!(
+            found,
+            [
+                (8, "message 4 has empty content"),
+                (8, "message 4 has the same role as message 3 it follows"),
+                (
+                    10,
+                    "system message 5 is not at the start of the conversation"
+                ),
+            ]
+        );
+
+        let calls = "\
+messages:
+  - content: Weather in Paris?
+  - role: assistant
+    tool_calls:
+      - id: call_1
+        type: function
+        function: {name: weather, arguments: '{}'}
+  - role: tool
+    tool_call_id: call_1
+    content: Sunny.
+";
+        assert!(lint(calls, true).is_empty());
+
+        let diagnostics = lint("messages:\n  - content: [unclosed\n", true);
+        assert_eq!(diagnostics[0].severity, Severity::Error);
+    }
+}
@@ -102,39 +102,579 @@
 //! and
 //! [its lower-level `ChatML` format](https://github.com/openai/openai-python/blob/main/chatml.md).
 //!
+//! Besides `system`, `user` and `assistant`,
+//! messages can have the newer `developer` and `tool` roles,
+//! assistant messages can hold `tool_calls`,
+//! tool messages the `tool_call_id` they answer,
+//! and any message can pass other fields to the API as is under `options`:
+//!
+//! ```yaml
+//! messages:
+//!   - role: developer
+//!     content: Answer with the weather tool.
+//!   - content: Weather in Paris?
+//!   - role: assistant
+//!     tool_calls:
+//!       - id: call_1
+//!         function: { name: weather, arguments: '{"city":"Paris"}' }
+//!   - role: tool
+//!     tool_call_id: call_1
+//!     content: Sunny.
+//!     options: { cache_control: { type: ephemeral } }
+//! ```
+//!
+//! Conversation files can also be kept in a library and referred to by name:
+//!
+//! ```console
+//! $ echo "Malcolm X" | answer @birthdates
+//! Malcolm X was born on May 19th, 1925.
+//! ```
+//!
+//! Names are looked up in `.answer/prompts` in the current directory,
+//! then in `answer/prompts` in your configuration directory
+//! (e.g., `~/.config/answer/prompts` on Linux),
+//! and finally among the prompts bundled with the application
+//! (see the [examples](https://github.com/schneiderfelipe/getanswe.rs/tree/main/examples)).
+//! Use `answer prompts list`, `answer prompts show <name>` and
+//! `answer prompts edit <name>` to manage them.
+//!
+//! To run the same conversation over many inputs,
+//! write them to a JSONL file,
+//! one `{"id": ..., "input": ...}` object per line,
+//! and use `answer batch`:
+//!
+//! ```console
+//! $ answer batch birthdates.yml --input people.jsonl --output results.jsonl --concurrency 8 --rate 60
+//! ```
+//!
+//! Each line of the output holds either an `output` or an `error` for its `id`.
+//! Running the same command again skips items that already have an `output`.
+//!
+//! Texts can also be embedded into a local vector store
+//! (`.answer/index.json` by default)
+//! and searched by similarity:
+//!
+//! ```console
+//! $ answer embed notes/*.md
+//! $ cat quotes.txt | answer embed
+//! $ answer search "civil rights" -k 3
+//! ```
+//!
+//! With `--index`,
+//! the excerpts of a directory of text files
+//! most relevant to the question are added to the conversation,
+//! and their sources are cited after the answer:
+//!
+//! ```console
+//! $ echo "How do I configure logging?" | answer --index docs/
+//! ```
+//!
+//! Embeddings of the directory are cached
+//! and only recomputed for files that changed.
+//!
+//! Long conversation files can be compacted automatically.
+//! Once a file with a `compaction` section
+//! holds more than `threshold` messages,
+//! older messages are summarized into a single system message
+//! named `memory`,
+//! and the file is rewritten:
+//!
+//! ```yaml
+//! # journal.yml
+//! compaction:
+//!   threshold: 20 # messages before compacting
+//!   keep: 6 # most recent messages kept verbatim
+//!   preserve: [system] # roles never summarized
+//!   prompt: Summarize the conversation so far. # optional
+//! messages:
+//!   - role: system
+//!     content: You are my journaling assistant.
+//! ```
+//!
+//! With `--save`,
+//! the question and the answer are appended to the conversation file.
+//! Messages may carry an `id`
+//! and the `parent` they follow (by default, the previous message),
+//! so a single file can hold alternative branches:
+//!
+//! ```console
+//! $ echo "Malcolm X" | answer birthdates.yml --save
+//! Malcolm X was born on May 19th, 1925.
+//! $ answer birthdates.yml --branch 2 --save < /dev/null
+//! May 19, 1925.
+//! $ answer tree birthdates.yml
+//! 1 system: You are a date of birth checker. Given the name of a person, your job is…
+//! └─ 2 user: Malcolm X
+//!    ├─ 3 assistant: Malcolm X was born on May 19th, 1925.
+//!    └─ 4 assistant: May 19, 1925.
+//! ```
+//!
+//! Messages without an explicit `id` are identified by their position.
+//! Without `--branch`, `answer` continues from the last message in the file.
+//!
+//! With `--extract`,
+//! only part of the answer is printed,
+//! such as its fenced code blocks (`code`, or `code:LANG` for one language),
+//! its first JSON object or array (`json`),
+//! or the matches of a regular expression (`regex:PATTERN`):
+//!
+//! ```console
+//! $ echo "Write a script listing the largest files" | answer --extract code:bash > script.sh
+//! ```
+//!
+//! Files can be edited by instruction with `answer edit`.
+//! The suggested changes are checked to apply cleanly
+//! and shown as a diff,
+//! and are only written after confirmation (or with `--yes`):
+//!
+//! ```console
+//! $ answer edit src/main.rs "Rename the `Bot` struct to `Assistant`"
+//! ```
+//!
+//! `answer` can also suggest shell commands for your operating system
+//! and shell,
+//! which you can then run, edit or cancel.
+//! Choosing to e**x**plain runs the command
+//! and sends its output back for an explanation:
+//!
+//! ```console
+//! $ answer --shell "find large log files"
+//! $ find /var/log -type f -size +100M
+//! [r]un, e[x]plain, [e]dit or [c]ancel?
+//! ```
+//!
+//! Messages can also include images,
+//! either in conversation files
+//! (as a list of `text` and `image` parts)
+//! or attached to the question with `--image`.
+//! Local images are sent inline,
+//! read relative to the conversation file
+//! (or to the current directory for `--image`),
+//! and conversations with images go to a vision-capable model:
+//!
+//! ```console
+//! $ echo "What breed is this dog?" | answer --image dog.jpg
+//! ```
+//!
+//! ```yaml
+//! messages:
+//!   - content:
+//!       - text: What is in this picture?
+//!       - image: cat.png
+//! ```
+//!
+//! Answers can be spoken with `--speak`,
+//! which writes the audio to the given file
+//! (or to the standard output, with the text going to the standard error).
+//! Texts can also be spoken directly with `answer speak`.
+//! Together with [`murmur`](https://crates.io/crates/murmur),
+//! this makes for a voice round trip:
+//!
+//! ```console
+//! $ echo "Tell me a joke." | answer --speak | mpv -
+//! $ answer speak "Hello, world!" --voice nova --audio-format opus -o hello.opus
+//! ```
+//!
+//! Questions can be checked before being sent,
+//! with the moderation endpoint (`--moderate`),
+//! a local policy file of forbidden keywords and patterns (`--policy`),
+//! or both.
+//! With `--moderate-reply`,
+//! answers are also checked before being shown.
+//! Flagged content makes `answer` exit with code 3:
+//!
+//! ```console
+//! $ echo "What is the salary of Alice?" | answer --policy policy.yml
+//! Error: content was flagged by moderation: salary
+//! ```
+//!
+//! ```yaml
+//! # policy.yml
+//! keywords: [password, salary] # matched as whole words, ignoring case
+//! patterns: ['\b\d{3}-\d{2}-\d{4}\b'] # regular expressions
+//! ```
+//!
+//! Secrets piped into `answer` can be kept from being sent.
+//! With `--redact`,
+//! private keys, API keys, tokens, passwords and email addresses
+//! are replaced by placeholders such as `[REDACTED_EMAIL_1]` beforehand,
+//! and `--redact-pattern` adds regular expressions of your own
+//! (only their `secret` group is replaced, if any).
+//! With `--unredact`,
+//! placeholders in the answer are replaced back by the original values:
+//!
+//! ```console
+//! $ cat config.env | answer --redact --unredact
+//! ```
+//!
+//! Prompts can be guarded against regressions with `answer test`.
+//! Each test file names a conversation,
+//! an input,
+//! and assertions on the answer
+//! (`contains`, `regex`, `json` path equality, `max_length`,
+//! or a `rubric` graded by the model):
+//!
+//! ```yaml
+//! # tests/pwd.yml
+//! conversation: ../act-as-a-linux-terminal.yml # relative to this file
+//! input: pwd
+//! assert:
+//!   - regex: /home/\w+
+//!   - max_length: 200
+//!   - rubric: Only shows terminal output, without explanations.
+//! ```
+//!
+//! ```console
+//! $ answer test tests/*.yml --junit report.xml
+//! PASS pwd
+//!
+//! 1 passed, 0 failed
+//! ```
+//!
+//! With `--replay FILE`,
+//! tests answer with replies recorded earlier instead of calling the API,
+//! recording any missing ones with `--record`,
+//! and with `--mock`,
+//! every reply simply repeats the input,
+//! so rubrics are skipped.
+//!
+//! Models can be compared side by side with `answer compare`,
+//! which sends the same conversation to every model concurrently
+//! and shows their replies in columns,
+//! along with latency and token usage
+//! (or as a JSON object keyed by model, with `--json`):
+//!
+//! ```console
+//! $ echo "Malcolm X" | answer compare --model gpt-3.5-turbo --model gpt-4 birthdates.yml
+//! gpt-3.5-turbo                          │ gpt-4
+//! 0.84s, 52+12=64 tokens                 │ 2.31s, 52+9=61 tokens
+//! ────────────────────────────────────── │ ──────────────────────────────────────
+//! Malcolm X was born on May 19th, 1925.  │ May 19, 1925.
+//! ```
+//!
+//! Every answer is logged to a local SQLite database,
+//! including those of subcommands such as `answer batch` or `answer test`,
+//! with its parameters and token usage
+//! (unless `--no-history` is given),
+//! so it can be found again with `answer history`:
+//!
+//! ```console
+//! $ answer history search 1925
+//!    12  2023-05-19 10:21:07  Malcolm X
+//! $ answer history show 12
+//! $ answer history rerun 12
+//! ```
+//!
+//! Answers can also be served over HTTP with `answer serve`.
+//! Conversations (in YAML or JSON) posted to `/answer`,
+//! and user messages posted to `/prompts/{name}`,
+//! are answered as a stream of server-sent events:
+//!
+//! ```console
+//! $ answer serve --port 8080 &
+//! $ curl -N localhost:8080/answer -d '{"messages": [{"content": "Hi!"}]}'
+//! data: Hello
+//!
+//! data: !
+//!
+//! event: done
+//! data:
+//! ```
+//!
+//! Served conversations are logged in the history,
+//! spent from the budget
+//! and redacted with `--redact`,
+//! but `--moderate` does not apply to them.
+//! Posted conversations cannot use MCP servers, local images,
+//! `steps` or `compaction`.
+//!
+//! Conversation files can also list
+//! [Model Context Protocol](https://modelcontextprotocol.io) servers,
+//! either commands speaking over their standard input and output
+//! or local HTTP endpoints.
+//! Their tools and resources are made available to the model while replying:
+//!
+//! ```yaml
+//! mcp:
+//!   - name: files
+//!     command: npx
+//!     args: [-y, "@modelcontextprotocol/server-filesystem", "."]
+//!   - name: tracker
+//!     url: http://localhost:3000/mcp
+//! messages:
+//!   - role: system
+//!     content: You help me keep track of deadlines.
+//! ```
+//!
+//! The other way around,
+//! `answer mcp-serve` publishes conversation files
+//! (by default, the library of named prompts)
+//! as an MCP server over the standard input and output.
+//! Each conversation becomes a tool replying to a user message,
+//! and a prompt,
+//! so editors and agents speaking MCP can use them:
+//!
+//! ```json
+//! {
+//!   "mcpServers": {
+//!     "prompts": { "command": "answer", "args": ["mcp-serve", "examples"] }
+//!   }
+//! }
+//! ```
+//!
+//! ## Prompt chains
+//!
+//! A conversation file can declare `steps` instead of messages,
+//! each step being its own conversation whose input refers to the
+//! standard input (`{{input}}`) or to the answers of earlier steps
+//! (`{{id}}`, with ids made of letters, digits, `_` and `-`):
+//!
+//! ```yaml
+//! steps:
+//!   - id: outline
+//!     conversation: outline.yml # relative to this file, or @name
+//!     input: '{{input}}'
+//!   - id: draft
+//!     messages:
+//!       - role: system
+//!         content: You turn outlines into drafts.
+//!     input: '{{outline}}'
+//!   - id: title
+//!     conversation: '@titles'
+//!     input: '{{outline}}'
+//!   - id: article
+//!     input: "# {{title}}\n\n{{draft}}"
+//! ```
+//!
+//! Steps run in order,
+//! except those with their inputs ready run at the same time
+//! (`draft` and `title` above),
+//! and the answer of the last step is written out.
+//! Each step is spent from the budget,
+//! has its answer checked with `--moderate-reply`,
+//! and is logged in the history,
+//! and `--save` also writes the answers to a directory next to the file
+//! (`article.steps/outline.txt` and so on).
+//! `--branch`, `--image`, `--index` and `--speak` cannot be used with chains.
+//!
+//! ## Spend limits
+//!
+//! Every request is recorded in a local ledger with its estimated cost,
+//! under the profile given by `--profile` (`default` otherwise),
+//! whether it answers a question,
+//! runs a subcommand,
+//! compacts a conversation
+//! or calls tools.
+//! Daily and monthly budgets per profile are set in `answer/budget.yml`
+//! in your configuration directory:
+//!
+//! ```yaml
+//! profiles:
+//!   default:
+//!     daily: 0.50 # dollars
+//!     monthly: 5
+//!   work:
+//!     monthly: 50
+//!     max_tokens: 2048 # longest answer, 1024 by default
+//! ```
+//!
+//! Before every request,
+//! the maximum cost is estimated from the length of the conversation
+//! and the longest answer allowed (`--max-tokens`).
+//! If it would exceed a budget,
+//! `answer` asks for confirmation on a terminal, and refuses otherwise.
+//! `batch`, `compare`, `serve`, `mcp-serve` and prompt chains,
+//! which make requests concurrently, always refuse.
+//! `answer budget` shows the spending of each profile.
+//!
+//! ## Linting conversations
+//!
+//! `answer lint` checks conversation files for mistakes,
+//! such as misspelled roles or keys,
+//! empty messages,
+//! or consecutive messages with the same role:
+//!
+//! ```console
+//! $ answer lint examples/*.yml
+//! examples/birthday.yml:4:5: error: unknown role "asistant", did you mean "assistant"? (fixable: "role: assistant")
+//! ```
+//!
+//! Unknown keys are denied with `--strict`,
+//! and misspelled roles and keys are fixed in place with `--fix`.
+//!
+//! ## Plugins
+//!
+//! Like `git`,
+//! `answer foo` runs an `answer-foo` executable found on your `PATH`
+//! with the remaining arguments,
+//! so custom workflows can be added without changing `answer` itself.
+//! Global options such as `--profile` or `--redact` can come before the
+//! plugin name.
+//! Plugins get the resolved configuration in environment variables
+//! (`ANSWER_BIN`, `ANSWER_VERSION`, `ANSWER_MODEL`, `ANSWER_PROFILE`,
+//! the budget and redaction settings, `ANSWER_CONFIG_DIR` and
+//! `ANSWER_DATA_DIR`),
+//! and the conversation named by their first YAML file or `@name` argument
+//! as JSON in `ANSWER_CONVERSATION`:
+//!
+//! ```console
+//! $ cat ~/.local/bin/answer-roles
+//! #!/bin/sh
+//! echo "$ANSWER_CONVERSATION" | jq -r '.messages[].role'
+//! $ answer roles @birthdates
+//! system
+//! ```
+//!
+//! ## Exit codes
+//!
+//! Failures are told apart by exit code,
+//! so that scripts can react to them:
+//!
+//! | Code | Kind               | Meaning                                     |
+//! |------|--------------------|---------------------------------------------|
+//! | 1    | `other`            | any other failure                           |
+//! | 2    | `usage`            | invalid command-line arguments              |
+//! | 3    | `flagged`          | content was flagged by moderation           |
+//! | 4    | `missing_key`      | `OPENAI_API_KEY` is not set                 |
+//! | 5    | `auth`             | the API key was rejected                    |
+//! | 6    | `rate_limited`     | too many requests, or quota exceeded        |
+//! | 7    | `context_too_long` | the conversation does not fit the model     |
+//! | 8    | `invalid_input`    | invalid files, or unknown names or messages |
+//! | 9    | `network`          | the API could not be reached                |
+//! | 10   | `over_budget`      | the answer could exceed a spending budget   |
+//! | 130  | `cancelled`        | interrupted by the user                     |
+//!
+//! With `--error-format json`,
+//! errors are reported on the standard error as JSON objects instead:
+//!
+//! ```console
+//! $ echo "Hello" | answer --error-format json
+//! {"error":{"code":4,"kind":"missing_key","message":"could not obtain environment variable: environment variable not found"}}
+//! ```
+//!
 //! ## Unsafe code usage
 //!
 //! This project forbids unsafe code usage.
 
 #![forbid(unsafe_code)]
 
+mod batch;
+mod budget;
+mod chain;
+mod compaction;
+mod compare;
+mod content;
+mod edit;
+mod embed;
+mod exit;
+mod extract;
+mod history;
+mod index;
+mod lint;
+mod mcp;
+mod mcp_server;
+mod moderation;
+mod plugin;
+mod prompts;
+mod provider;
+mod redaction;
+mod serve;
+mod shell;
+mod speech;
+mod testing;
+mod tree;
+
 use std::env;
+use std::fmt;
 use std::fs::File;
 use std::io::Read;
 use std::io::{self};
+use std::path::Path;
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::process::ExitCode;
+use std::process::ExitStatus;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::SystemTime;
 
 use async_openai::error::OpenAIError;
 use async_openai::types::ChatCompletionRequestMessage;
-use async_openai::types::CreateChatCompletionRequestArgs;
-use async_openai::types::Role;
+use async_openai::types::ChatCompletionResponseStream;
+use async_openai::types::CreateEmbeddingRequestArgs;
+use async_openai::types::EmbeddingInput;
+use async_openai::types::Usage;
 use async_openai::Client;
 use clap::Parser;
+use clap::Subcommand;
+use eventsource_stream::EventStreamError;
+use eventsource_stream::Eventsource;
+use futures::future;
+use futures::future::Either;
 use futures::StreamExt;
+use reqwest::StatusCode;
+use rustyline::error::ReadlineError;
 use serde::Deserialize;
 use serde::Serialize;
+use serde_json::json;
 use thiserror::Error;
 use tokio::io::AsyncReadExt;
 use tokio::io::AsyncWrite;
 use tokio::io::AsyncWriteExt;
 
+use crate::batch::BatchCommand;
+use crate::budget::BudgetCommand;
+use crate::budget::Period;
+use crate::chain::Step;
+use crate::compaction::Compaction;
+use crate::compare::CompareCommand;
+use crate::content::Content;
+use crate::edit::EditCommand;
+use crate::edit::PatchError;
+use crate::embed::EmbedCommand;
+use crate::embed::SearchCommand;
+use crate::exit::stream_error;
+use crate::exit::ErrorFormat;
+use crate::history::History;
+use crate::history::HistoryCommand;
+use crate::history::Invocation;
+use crate::lint::LintCommand;
+use crate::mcp::McpError;
+use crate::mcp::Server;
+use crate::mcp_server::McpServeCommand;
+use crate::moderation::ModerationOptions;
+use crate::plugin::Plugin;
+use crate::prompts::PromptsCommand;
+use crate::provider::Provider;
+use crate::redaction::RedactionOptions;
+use crate::redaction::Redactor;
+use crate::redaction::Restorer;
+use crate::serve::ServeCommand;
+use crate::speech::SpeakCommand;
+use crate::speech::SpeechOptions;
+use crate::testing::TestCommand;
+use crate::tree::TreeCommand;
+
 /// The context of a conversation.
 ///
 /// It can be used for building prompts or storing chat history.
 #[derive(Clone, Debug, Default, Serialize, Deserialize)]
 struct Conversation {
+    /// Settings for compacting this [`Conversation`] when it grows too long.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    compaction: Option<Compaction>,
+    /// MCP [`Server`]s whose tools are available while replying.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    mcp: Vec<Server>,
     /// [`Message`]s in this [`Conversation`].
     #[serde(default, skip_serializing_if = "Vec::is_empty")]
     messages: Vec<Message>,
+    /// [`Step`]s of the chain this [`Conversation`] declares, if any.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    steps: Vec<Step>,
+    /// The file this [`Conversation`] was read from, if any.
+    #[serde(skip)]
+    path: Option<PathBuf>,
 }
 
 impl Conversation {
@@ -144,6 +684,35 @@ impl Conversation {
         self.messages.push(message);
     }
 
+    /// Determine whether any [`Message`] in this [`Conversation`] has images.
+    #[inline]
+    fn has_images(&self) -> bool {
+        self.messages
+            .iter()
+            .any(|message| message.content.has_images())
+    }
+
+    /// Determine whether any [`Message`] in this [`Conversation`] has images
+    /// read from local files.
+    #[inline]
+    fn has_local_images(&self) -> bool {
+        self.messages
+            .iter()
+            .any(|message| message.content.has_local_images())
+    }
+
+    /// Convert the [`Message`]s of this [`Conversation`] to the JSON expected
+    /// by the chat completion API.
+    #[inline]
+    fn to_api(&self) -> io::Result<Vec<serde_json::Value>> {
+        // Local images are relative to the conversation file.
+        let directory = self.path.as_deref().and_then(Path::parent);
+        self.messages
+            .iter()
+            .map(|message| message.to_api(directory))
+            .collect()
+    }
+
     /// Parse a [`Conversation`] from a [`Read`]er.
     #[inline]
     fn from_reader<R>(reader: R) -> Result<Self, serde_yaml::Error>
@@ -152,6 +721,77 @@ impl Conversation {
     {
         serde_yaml::from_reader(reader)
     }
+
+    /// Write this [`Conversation`] back to the file it was read from, if any.
+    #[inline]
+    fn save(&self) -> Result<(), CliError> {
+        if let Some(path) = &self.path {
+            serde_yaml::to_writer(File::create(path)?, self)?;
+        }
+        Ok(())
+    }
+}
+
+/// The role of the author of a [`Message`].
+///
+/// This is a redefinition of [`async_openai::types::Role`],
+/// which lacks the newer roles.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum Role {
+    /// Instructions from the system.
+    System,
+    /// Instructions from the developer,
+    /// superseding system instructions in newer models.
+    Developer,
+    /// A message from the user.
+    #[default]
+    User,
+    /// A reply from the model.
+    Assistant,
+    /// The output of a tool called by the model.
+    Tool,
+}
+
+impl fmt::Display for Role {
+    #[inline]
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::System => write!(f, "system"),
+            Self::Developer => write!(f, "developer"),
+            Self::User => write!(f, "user"),
+            Self::Assistant => write!(f, "assistant"),
+            Self::Tool => write!(f, "tool"),
+        }
+    }
+}
+
+/// A call to a tool requested by the model in an assistant [`Message`].
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+struct ToolCall {
+    /// The identifier of the call,
+    /// referred to by the tool [`Message`] holding its output.
+    id: String,
+    /// The type of the tool.
+    #[serde(rename = "type", default = "default_tool_type")]
+    kind: String,
+    /// The function called.
+    function: FunctionCall,
+}
+
+/// A function called by the model.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+struct FunctionCall {
+    /// The name of the function.
+    name: String,
+    /// The arguments of the call, as a JSON object.
+    arguments: String,
+}
+
+/// Get the type of [`ToolCall`]s when not given.
+#[inline]
+fn default_tool_type() -> String {
+    "function".to_owned()
 }
 
 /// A [`Conversation`] message.
@@ -164,11 +804,28 @@ struct Message {
     #[serde(default, skip_serializing_if = "is_user")]
     role: Role,
     /// The contents of the [`Message`].
-    #[serde(default, skip_serializing_if = "String::is_empty")]
-    content: String,
+    #[serde(default, skip_serializing_if = "Content::is_empty")]
+    content: Content,
     /// The name of the author in a multi-agent [`Conversation`].
     #[serde(default, skip_serializing_if = "Option::is_none")]
     name: Option<String>,
+    /// The identifier of the [`Message`] in a branching [`Conversation`].
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    id: Option<String>,
+    /// The identifier of the [`Message`] this one follows,
+    /// if not the previous one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    parent: Option<String>,
+    /// The [`ToolCall`]s requested in an assistant [`Message`].
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    tool_calls: Vec<ToolCall>,
+    /// The identifier of the [`ToolCall`] whose output a tool [`Message`]
+    /// holds.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    tool_call_id: Option<String>,
+    /// Other fields sent along with the [`Message`] as is.
+    #[serde(default, skip_serializing_if = "serde_json::Map::is_empty")]
+    options: serde_json::Map<String, serde_json::Value>,
 }
 
 impl Message {
@@ -180,27 +837,121 @@ impl Message {
     {
         Self {
             role: Role::User,
-            content: content.into(),
-            name: None,
+            content: Content::Text(content.into()),
+            ..Self::default()
+        }
+    }
+
+    /// Create a [`Message`] whose [`Role`] is assistant.
+    #[inline]
+    fn from_assistant<C>(content: C) -> Self
+    where
+        C: Into<String>,
+    {
+        Self {
+            role: Role::Assistant,
+            content: Content::Text(content.into()),
+            ..Self::default()
+        }
+    }
+
+    /// Create a [`Message`] whose [`Role`] is system.
+    #[inline]
+    fn from_system<C>(content: C) -> Self
+    where
+        C: Into<String>,
+    {
+        Self {
+            role: Role::System,
+            content: Content::Text(content.into()),
+            ..Self::default()
         }
     }
+
+    /// Convert this [`Message`] to the JSON expected by the chat completion
+    /// API,
+    /// reading local images relative to a directory, if any.
+    ///
+    /// Options never override the other fields.
+    #[inline]
+    fn to_api(&self, directory: Option<&Path>) -> io::Result<serde_json::Value> {
+        let mut value = self.options.clone();
+        value.insert("role".to_owned(), json!(self.role));
+        value.insert("content".to_owned(), self.content.to_api(directory)?);
+        if let Some(name) = &self.name {
+            value.insert("name".to_owned(), json!(name));
+        }
+        if !self.tool_calls.is_empty() {
+            value.insert("tool_calls".to_owned(), json!(self.tool_calls));
+        }
+        if let Some(id) = &self.tool_call_id {
+            value.insert("tool_call_id".to_owned(), json!(id));
+        }
+        Ok(serde_json::Value::Object(value))
+    }
 }
 
 impl From<Message> for ChatCompletionRequestMessage {
     /// Convert a [`Message`] into a [`ChatCompletionRequestMessage`].
+    ///
+    /// Newer roles fall back to the closest older ones,
+    /// and newer fields and images are dropped,
+    /// so [`Message`]s should be sent as JSON instead where they can.
     #[inline]
     fn from(message: Message) -> Self {
         Self {
-            role: message.role,
-            content: message.content,
+            role: match message.role {
+                Role::System | Role::Developer => async_openai::types::Role::System,
+                Role::User | Role::Tool => async_openai::types::Role::User,
+                Role::Assistant => async_openai::types::Role::Assistant,
+            },
+            content: message.content.to_string(),
             name: message.name,
         }
     }
 }
 
+/// Model used for [`Conversation`]s without images.
+const CHAT_MODEL: &str = "gpt-3.5-turbo";
+
+/// Model used for [`Conversation`]s with images.
+const VISION_MODEL: &str = "gpt-4o";
+
 /// A robot that answers questions in plain text.
-#[derive(Debug, Default, Serialize, Deserialize)]
-struct Bot {}
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+struct Bot {
+    /// Maximum number of tokens in replies, if limited.
+    #[serde(default)]
+    max_tokens: Option<u16>,
+    /// Where replies come from.
+    #[serde(skip)]
+    provider: Provider,
+    /// Whether replies are logged in the [`History`].
+    #[serde(skip)]
+    history: bool,
+    /// The model that replies instead of the default one, if any.
+    #[serde(skip)]
+    model: Option<String>,
+    /// The profile whose budget requests are spent from, if any.
+    #[serde(skip)]
+    profile: Option<String>,
+    /// Whether requests over budget can be confirmed on a terminal,
+    /// which only one request at a time may do.
+    #[serde(skip)]
+    confirms: bool,
+    /// The [`Redactor`] replacing secrets in everything sent, if any.
+    #[serde(skip)]
+    redactor: Option<Arc<Mutex<Redactor>>>,
+}
+
+/// A reply of a [`Bot`].
+#[derive(Debug, Default)]
+struct Reply {
+    /// The text of the reply.
+    text: String,
+    /// Token usage reported for the request, if any.
+    usage: Option<Usage>,
+}
 
 /// An error that came from [`Bot`].
 #[derive(Debug, Error)]
@@ -209,54 +960,242 @@ enum BotError {
     Var(#[from] env::VarError),
     #[error("could not exchange data with OpenAI: {0}")]
     OpenAI(#[from] OpenAIError),
+    #[error("could not exchange data with OpenAI: {message}")]
+    Status { status: StatusCode, message: String },
     #[error("could not perform an input or output operation: {0}")]
     Io(#[from] io::Error),
+    #[error("content was flagged by moderation: {}", .0.join(", "))]
+    Flagged(Vec<String>),
+    #[error("could not use an MCP server: {0}")]
+    Mcp(#[from] McpError),
+    #[error("could not find a recorded reply for request {0}")]
+    NotRecorded(String),
+    #[error(transparent)]
+    Budget(Box<CliError>),
 }
 
 impl Bot {
+    /// Create a [`Client`] configured from the environment.
+    #[inline]
+    fn client(&self) -> Result<Client, BotError> {
+        Ok(Client::default().with_api_key(env::var("OPENAI_API_KEY")?))
+    }
+
+    /// Log an [`Invocation`] in the [`History`],
+    /// unless disabled.
+    ///
+    /// Failures are only warned about,
+    /// as they should not lose the reply.
+    #[inline]
+    fn log(&self, invocation: &Invocation<'_>) {
+        if !self.history {
+            return;
+        }
+        if let Err(err) = History::open().and_then(|mut history| history.log(invocation)) {
+            log::warn!("could not log the invocation: {err}");
+        }
+    }
+
+    /// Get the model that replies in the context of a [`Conversation`].
+    #[inline]
+    fn model(&self, conversation: &Conversation) -> &str {
+        match &self.model {
+            Some(model) => model,
+            None if conversation.has_images() => VISION_MODEL,
+            None => CHAT_MODEL,
+        }
+    }
+
     /// Reply, in the context of a [`Conversation`], to the given
     /// [`AsyncWrite`]r.
+    ///
+    /// The complete reply is also returned once done.
     #[inline]
     async fn reply_to_writer<W>(
+        &self,
+        conversation: &Conversation,
+        writer: W,
+    ) -> Result<String, BotError>
+    where
+        W: AsyncWrite + Send + Unpin,
+    {
+        Ok(self.reply(conversation, writer).await?.text)
+    }
+
+    /// Reply, in the context of a [`Conversation`], to the given
+    /// [`AsyncWrite`]r.
+    ///
+    /// The complete [`Reply`] is also returned once done,
+    /// with its token usage.
+    #[inline]
+    async fn reply<W>(&self, conversation: &Conversation, mut writer: W) -> Result<Reply, BotError>
+    where
+        W: AsyncWrite + Send + Unpin,
+    {
+        let conversation = &*self.redact_conversation(conversation);
+        let canned = match &self.provider {
+            Provider::Live => None,
+            Provider::Mock => Some(provider::mock_reply(conversation)),
+            Provider::Replay(replay) => {
+                let key = provider::key(self.model(conversation), self.max_tokens, conversation)?;
+                match replay.get(&key) {
+                    Some(reply) => Some(reply),
+                    None if replay.records => {
+                        let reply = self.request_reply(conversation, writer).await?;
+                        replay.record(key, reply.text.clone())?;
+                        return Ok(reply);
+                    }
+                    None => return Err(BotError::NotRecorded(key)),
+                }
+            }
+        };
+        match canned {
+            Some(text) => {
+                writer.write_all(text.as_bytes()).await?;
+                writer.flush().await?;
+                Ok(Reply { text, usage: None })
+            }
+            None => self.request_reply(conversation, writer).await,
+        }
+    }
+
+    /// Reply, in the context of a [`Conversation`], to the given
+    /// [`AsyncWrite`]r,
+    /// requesting the reply from the API.
+    #[inline]
+    async fn request_reply<W>(
         &self,
         conversation: &Conversation,
         mut writer: W,
-    ) -> Result<(), BotError>
+    ) -> Result<Reply, BotError>
     where
         W: AsyncWrite + Send + Unpin,
     {
-        let mut stream = Client::default()
-            .with_api_key(env::var("OPENAI_API_KEY")?)
-            .chat()
-            .create_stream({
-                CreateChatCompletionRequestArgs::default()
-                    .model("gpt-3.5-turbo")
-                    .temperature(0.0)
-                    .messages(
-                        conversation
-                            .messages
-                            .iter()
-                            .cloned()
-                            .map(Into::into)
-                            .collect::<Vec<_>>(),
-                    )
-                    .build()?
-            })
-            .await?;
+        if !conversation.mcp.is_empty() {
+            return self.reply_with_tools(conversation, writer).await;
+        }
+
+        let model = self.model(conversation);
+        let prompt_tokens = budget::count_conversation_tokens(conversation);
+        let (max_tokens, reservation) = self.check_budget(model, prompt_tokens, self.max_tokens)?;
 
+        let mut stream = self.create_json_stream(conversation, max_tokens).await?;
+        let mut reply = Reply::default();
         while let Some(response) = stream.next().await {
-            for content in response?
+            let response = response?;
+            if response.usage.is_some() {
+                reply.usage = response.usage;
+            }
+            for content in response
                 .choices
                 .into_iter()
                 .filter_map(|choice| choice.delta.content)
             {
                 writer.write_all(content.as_bytes()).await?;
+                reply.text.push_str(&content);
             }
 
             writer.flush().await?;
         }
 
-        Ok(())
+        reservation.settle(&reply.text, reply.usage.as_ref());
+        Ok(reply)
+    }
+
+    /// Create a chat completion stream for a [`Conversation`],
+    /// which ends with the token usage.
+    ///
+    /// The request is made by hand,
+    /// as [`ChatCompletionRequestMessage`] only supports text and older
+    /// roles,
+    /// and [`CreateChatCompletionRequest`](async_openai::types::CreateChatCompletionRequest)
+    /// cannot ask for the usage of streams.
+    ///
+    /// A failed request is an [`OpenAIError::ApiError`] when the API says
+    /// why,
+    /// so that it can be classified like any other.
+    #[inline]
+    async fn create_json_stream(
+        &self,
+        conversation: &Conversation,
+        max_tokens: Option<u16>,
+    ) -> Result<ChatCompletionResponseStream, BotError> {
+        let client = self.client()?;
+        let messages = conversation.to_api()?;
+
+        let response = reqwest::Client::new()
+            .post(format!("{base}/chat/completions", base = client.api_base()))
+            .bearer_auth(client.api_key())
+            .json(&json!({
+                "model": self.model(conversation),
+                "temperature": 0.0,
+                "max_tokens": max_tokens,
+                "stream": true,
+                "stream_options": { "include_usage": true },
+                "messages": messages,
+            }))
+            .send()
+            .await
+            .map_err(OpenAIError::Reqwest)?;
+        let status = response.status();
+        if !status.is_success() {
+            let body = response.bytes().await.map_err(OpenAIError::Reqwest)?;
+            return Err(stream_error(status, &body));
+        }
+
+        Ok(Box::pin(
+            response
+                .bytes_stream()
+                .eventsource()
+                .take_while(|event| {
+                    future::ready(!matches!(event, Ok(event) if event.data == "[DONE]"))
+                })
+                .map(|event| match event {
+                    Ok(event) => {
+                        serde_json::from_str(&event.data).map_err(OpenAIError::JSONDeserialize)
+                    }
+                    Err(EventStreamError::Transport(err)) => Err(OpenAIError::Reqwest(err)),
+                    Err(err) => Err(OpenAIError::StreamError(err.to_string())),
+                }),
+        ))
+    }
+
+    /// Compute an embedding vector for each of the given texts.
+    #[inline]
+    async fn embed(&self, model: &str, texts: Vec<String>) -> Result<Vec<Vec<f32>>, BotError> {
+        let texts: Vec<_> = texts
+            .iter()
+            .map(|text| self.redact(text).into_owned())
+            .collect();
+        let prompt_tokens = texts.iter().map(|text| budget::count_tokens(text)).sum();
+        let (_, reservation) = self.check_budget(model, prompt_tokens, Some(0))?;
+
+        let mut response = self
+            .client()?
+            .embeddings()
+            .create(
+                CreateEmbeddingRequestArgs::default()
+                    .model(model)
+                    .input(EmbeddingInput::StringArray(texts))
+                    .build()?,
+            )
+            .await?;
+        log::debug!("{usage:?}", usage = response.usage);
+        reservation.settle(
+            "",
+            Some(&Usage {
+                prompt_tokens: response.usage.prompt_tokens,
+                completion_tokens: 0,
+                total_tokens: response.usage.total_tokens,
+            }),
+        );
+
+        response.data.sort_by_key(|embedding| embedding.index);
+        Ok(response
+            .data
+            .into_iter()
+            .map(|embedding| embedding.embedding)
+            .collect())
     }
 }
 
@@ -269,56 +1208,498 @@ impl Bot {
 #[command(author, version, about)]
 #[command(propagate_version = true)]
 struct Cli {
-    /// Path to a conversation YAML file.
-    #[arg(value_parser = parse_conversation)]
-    conversation: Option<Conversation>,
+    /// Path to a conversation YAML file,
+    /// or `@name` of a prompt in the library.
+    conversation: Option<String>,
+
+    /// Directory of text files to retrieve relevant excerpts from.
+    ///
+    /// Excerpts are added to the conversation and their sources are
+    /// printed after the answer.
+    #[arg(long, value_name = "DIR")]
+    index: Option<PathBuf>,
+
+    /// Identifier of the message to continue from,
+    /// instead of the last one.
+    ///
+    /// With an empty standard input,
+    /// the reply to that message is regenerated.
+    #[arg(long, value_name = "ID")]
+    branch: Option<String>,
+
+    /// Append the question and the answer to the conversation file.
+    #[arg(long)]
+    save: bool,
+
+    /// Ask for a shell command that does what is requested,
+    /// then run, edit or cancel it.
+    #[arg(
+        long,
+        value_name = "REQUEST",
+        conflicts_with_all = ["conversation", "index", "branch", "save"]
+    )]
+    shell: Option<String>,
+
+    /// Path or URL of an image to attach to the question.
+    ///
+    /// Conversations with images are sent to a vision-capable model.
+    #[arg(long = "image", value_name = "IMAGE")]
+    images: Vec<String>,
+
+    /// Speak the answer once complete,
+    /// writing the audio to a file,
+    /// or to the standard output if none is given.
+    ///
+    /// When the audio goes to the standard output,
+    /// the text of the answer goes to the standard error.
+    #[arg(
+        long,
+        value_name = "FILE",
+        num_args = 0..=1,
+        default_missing_value = speech::STDOUT,
+        conflicts_with = "shell"
+    )]
+    speak: Option<PathBuf>,
+
+    /// Speech options.
+    #[command(flatten)]
+    speech: SpeechOptions,
+
+    /// Maximum number of tokens in each answer.
+    #[arg(long, global = true, value_name = "TOKENS")]
+    max_tokens: Option<u16>,
+
+    /// Profile whose budget requests are spent from
+    /// [default: default].
+    #[arg(long, global = true)]
+    profile: Option<String>,
+
+    /// Print only part of the answer:
+    /// `code` or `code:LANG` for fenced code blocks,
+    /// `json` for the first JSON object or array,
+    /// or `regex:PATTERN` for the matches of a regular expression.
+    #[arg(long, value_name = "EXTRACTOR", conflicts_with = "shell")]
+    extract: Option<String>,
+
+    /// Do not log this invocation in the history.
+    #[arg(long, global = true)]
+    no_history: bool,
+
+    /// Moderation options.
+    #[command(flatten)]
+    moderation: ModerationOptions,
+
+    /// Redaction options.
+    #[command(flatten)]
+    redaction: RedactionOptions,
+
+    /// Subcommand to run instead of answering.
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    /// Format of reported errors.
+    #[arg(long, global = true, value_enum, default_value_t)]
+    error_format: ErrorFormat,
 
     /// Verbosity options.
     #[clap(flatten)]
     verbosity: clap_verbosity_flag::Verbosity,
 }
 
+/// Subcommands of [`Cli`].
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Manage the library of named prompts.
+    #[command(subcommand)]
+    Prompts(PromptsCommand),
+    /// Run a conversation over every input of a JSONL file.
+    Batch(BatchCommand),
+    /// Compute embeddings for standard input lines or files and store them.
+    Embed(EmbedCommand),
+    /// Find the stored items most similar to a query.
+    Search(SearchCommand),
+    /// Print the branch structure of a conversation.
+    Tree(TreeCommand),
+    /// Edit a file according to an instruction.
+    Edit(EditCommand),
+    /// Speak a text.
+    Speak(SpeakCommand),
+    /// Run prompt regression tests.
+    Test(TestCommand),
+    /// Compare the replies of several models to a conversation.
+    Compare(CompareCommand),
+    /// Browse the history of invocations.
+    #[command(subcommand)]
+    History(HistoryCommand),
+    /// Serve answers over HTTP.
+    Serve(ServeCommand),
+    /// Publish conversation files as an MCP server over the standard input
+    /// and output.
+    McpServe(McpServeCommand),
+    /// Check conversation files for mistakes.
+    Lint(LintCommand),
+    /// Show spending and budgets,
+    /// of the profile given by `--profile` or of every profile.
+    Budget(BudgetCommand),
+}
+
 /// An error that came from [`Cli`].
 #[derive(Debug, Error)]
 enum CliError {
     #[error("could not perform a serialization or deserialization operation: {0}")]
     Yaml(#[from] serde_yaml::Error),
+    #[error("could not perform a serialization or deserialization operation: {0}")]
+    Json(#[from] serde_json::Error),
+    #[error("could not parse line {line} of a JSONL file: {source}")]
+    Jsonl {
+        line: usize,
+        source: serde_json::Error,
+    },
     #[error("could not perform an input or output operation: {0}")]
     Io(#[from] io::Error),
+    #[error(transparent)]
+    Bot(#[from] BotError),
+    #[error("could not apply the suggested changes: {0}")]
+    Patch(#[from] PatchError),
+    #[error("store uses model {store:?}, but {requested:?} was requested")]
+    ModelMismatch { store: String, requested: String },
+    #[error("could not find a store at {0:?}")]
+    StoreNotFound(PathBuf),
+    #[error("could not read a line from the terminal: {0}")]
+    Readline(#[from] ReadlineError),
+    #[error("command failed with {0}")]
+    Command(ExitStatus),
+    #[error("could not find a message with identifier {0:?}")]
+    BranchNotFound(String),
+    #[error("could not find a prompt named {0:?}")]
+    PromptNotFound(String),
+    #[error("could not determine the user configuration directory")]
+    NoConfigDir,
+    #[error("editor {0:?} did not exit successfully")]
+    Editor(String),
+    #[error("could not compile a pattern: {0}")]
+    Regex(#[from] regex::Error),
+    #[error("{failed} of {total} tests failed")]
+    TestsFailed { failed: usize, total: usize },
+    #[error("could not access the history database: {0}")]
+    Sqlite(#[from] rusqlite::Error),
+    #[error("could not find invocation {0} in the history")]
+    InvocationNotFound(i64),
+    #[error("could not determine the user data directory")]
+    NoDataDir,
+    #[error("interrupted")]
+    Cancelled,
+    #[error("found {0} errors in conversation files")]
+    LintFailed(usize),
+    #[error(
+        "estimated cost of ${estimate:.4} would exceed the {period} budget of ${limit:.2} \
+         for profile {profile:?}, of which ${spent:.4} is spent"
+    )]
+    OverBudget {
+        profile: String,
+        period: Period,
+        limit: f64,
+        spent: f64,
+        estimate: f64,
+    },
+    #[error("found more than one step named {0:?}")]
+    DuplicateStep(String),
+    #[error("found step named {0:?}, but names may only have letters, digits, `_` and `-`")]
+    InvalidStep(String),
+    #[error("step {step:?} refers to {reference:?}, which is not an earlier step")]
+    UnknownStep { step: String, reference: String },
+    #[error("could not use {0} with a chain")]
+    UnsupportedInChain(&'static str),
+    #[error("could not parse extractor {0:?}, expected code, code:LANG, json or regex:PATTERN")]
+    UnknownExtractor(String),
+    #[error("could not find {0} in the answer")]
+    NothingExtracted(String),
+    #[error("could not run step {step:?}: {source}")]
+    StepFailed {
+        step: String,
+        #[source]
+        source: Box<BotError>,
+    },
 }
 
 /// Get a [`Conversation`] from a file [`Path`] by parsing.
+///
+/// Paths starting with `@` refer to named prompts in the library.
 #[inline]
 fn parse_conversation(path: &str) -> Result<Conversation, CliError> {
-    let file = File::open(path)?;
-    let conversation = Conversation::from_reader(file)?;
+    let conversation = if let Some(name) = path.strip_prefix('@') {
+        let prompt =
+            prompts::find(name).ok_or_else(|| CliError::PromptNotFound(name.to_owned()))?;
+        Conversation {
+            path: prompt.path.clone(),
+            ..Conversation::from_reader(prompt.contents()?.as_bytes())?
+        }
+    } else {
+        Conversation {
+            path: Some(path.into()),
+            ..Conversation::from_reader(File::open(path)?)?
+        }
+    };
     Ok(conversation)
 }
 
 /// Our beloved main function.
 #[tokio::main]
-async fn main() -> anyhow::Result<()> {
+async fn main() -> ExitCode {
     human_panic::setup_panic!();
 
-    let cli = Cli::parse();
+    let args = env::args_os().collect::<Vec<_>>();
+    if let Some(plugin) = Plugin::find(&args) {
+        init_logger(plugin.options());
+        let error_format = plugin.options().error_format;
+        return match plugin.run().await {
+            Ok(code) => ExitCode::from(code),
+            Err(err) => {
+                err.report(error_format);
+                ExitCode::from(err.kind().code())
+            }
+        };
+    }
+
+    let cli = match Cli::try_parse_from(&args) {
+        Ok(cli) => cli,
+        Err(err) => return ExitCode::from(exit::report_usage(&err, &args)),
+    };
+    init_logger(&cli);
+    log::debug!("{cli:#?}");
+
+    let error_format = cli.error_format;
+    let result = match future::select(Box::pin(run(cli)), Box::pin(tokio::signal::ctrl_c())).await {
+        Either::Left((result, _)) => result,
+        Either::Right(_) => Err(CliError::Cancelled),
+    };
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            err.report(error_format);
+            ExitCode::from(err.kind().code())
+        }
+    }
+}
+
+/// Log at the verbosity asked for by a [`Cli`].
+#[inline]
+fn init_logger(cli: &Cli) {
     pretty_env_logger::formatted_builder()
         .filter_level(cli.verbosity.log_level_filter())
         .init();
-    log::debug!("{cli:#?}");
+}
+
+/// Run the [`Cli`].
+#[inline]
+async fn run(cli: Cli) -> Result<(), CliError> {
+    // Secrets are redacted from everything sent,
+    // starting with compaction.
+    let bot = Bot {
+        max_tokens: cli.max_tokens,
+        history: !cli.no_history,
+        profile: Some(
+            cli.profile
+                .clone()
+                .unwrap_or_else(|| budget::DEFAULT_PROFILE.to_owned()),
+        ),
+        confirms: true,
+        redactor: cli.redaction.redactor().map(Mutex::new).map(Arc::new),
+        ..Bot::default()
+    };
+    // Concurrent requests and servers cannot ask for confirmation.
+    let unattended = Bot {
+        confirms: false,
+        ..bot.clone()
+    };
+    if let Some(command) = cli.command {
+        match command {
+            Command::Prompts(command) => command.run()?,
+            Command::Batch(command) => command.run(&unattended).await?,
+            Command::Embed(command) => command.run(&bot).await?,
+            Command::Search(command) => command.run(&bot).await?,
+            Command::Tree(command) => command.run()?,
+            Command::Edit(command) => command.run(&bot).await?,
+            Command::Speak(command) => command.run(&bot).await?,
+            Command::Test(command) => command.run(bot).await?,
+            Command::Compare(command) => command.run(&unattended).await?,
+            Command::History(command) => command.run(&bot).await?,
+            Command::Serve(command) => command.run(unattended).await?,
+            Command::McpServe(command) => command.run(unattended).await?,
+            Command::Lint(command) => command.run()?,
+            Command::Budget(command) => command.run(cli.profile.as_deref())?,
+        }
+        return Ok(());
+    }
 
-    let mut conversation = cli.conversation.unwrap_or_default();
+    if let Some(request) = &cli.shell {
+        shell::suggest(&bot, request).await?;
+        return Ok(());
+    }
+
+    let moderation = cli.moderation.moderation()?;
+    let extractor = cli
+        .extract
+        .as_deref()
+        .map(extract::parse_extractor)
+        .transpose()?;
+    let mut conversation = cli
+        .conversation
+        .as_deref()
+        .map(parse_conversation)
+        .transpose()?
+        .unwrap_or_default();
+    if !conversation.steps.is_empty() {
+        let unsupported = [
+            ("--branch", cli.branch.is_some()),
+            ("--image", !cli.images.is_empty()),
+            ("--index", cli.index.is_some()),
+            ("--speak", cli.speak.is_some()),
+        ];
+        if let Some(&(flag, _)) = unsupported.iter().find(|&&(_, used)| used) {
+            return Err(CliError::UnsupportedInChain(flag));
+        }
+    }
+    if let Some(compaction) = conversation.compaction.clone() {
+        if conversation.is_branched() {
+            log::warn!("branching conversations are not compacted");
+        } else if compaction.compact(&bot, &mut conversation).await? {
+            conversation.save()?;
+        }
+    }
 
-    conversation.push({
-        let mut content = String::new();
-        tokio::io::stdin().read_to_string(&mut content).await?;
+    let node = match &cli.branch {
+        Some(id) => Some(
+            conversation
+                .find(id)
+                .ok_or_else(|| CliError::BranchNotFound(id.clone()))?,
+        ),
+        None => conversation.messages.len().checked_sub(1),
+    };
+    let mut context = conversation.thread(node);
 
-        Message::from_user(content)
+    // The question is kept as written,
+    // and only redacted when sent.
+    let mut content = String::new();
+    tokio::io::stdin().read_to_string(&mut content).await?;
+    if moderation.is_enabled() && !content.trim().is_empty() {
+        moderation.check(&bot, &content).await?;
+    }
+
+    if !conversation.steps.is_empty() {
+        let mut output = chain::run(&bot, &conversation, &content, &moderation, cli.save).await?;
+        if let Some(redactor) = bot.redactor().filter(|redactor| redactor.restores) {
+            output = redactor.restore(&output);
+        }
+        if let Some(extractor) = &extractor {
+            output = extractor.extract(&output)?;
+        }
+        let mut stdout = tokio::io::stdout();
+        stdout.write_all(output.as_bytes()).await?;
+        stdout.flush().await?;
+        return Ok(());
+    }
+
+    let sources = match &cli.index {
+        Some(directory) => index::augment(&bot, directory, &mut context, &content).await?,
+        None => Vec::new(),
+    };
+    let question = if cli.branch.is_some() && content.trim().is_empty() {
+        None
+    } else {
+        let mut question = Message::from_user(content);
+        if !cli.images.is_empty() {
+            question.content = question.content.with_images(cli.images)?;
+        }
+        context.push(question.clone());
+        Some(question)
+    };
+
+    let context = bot.redact_conversation(&context).into_owned();
+    let redactor = bot.redactor();
+
+    let model = bot.model(&context);
+
+    let mut writer: Pin<Box<dyn AsyncWrite + Send>> = match &cli.speak {
+        Some(path) if path.as_os_str() == speech::STDOUT => Box::pin(tokio::io::stderr()),
+        _ => Box::pin(tokio::io::stdout()),
+    };
+    let started = SystemTime::now();
+    let mut extracted = Ok(());
+    let restorer = redactor.as_ref().filter(|redactor| redactor.restores);
+    let restore =
+        |text: &str| restorer.map_or_else(|| text.to_owned(), |redactor| redactor.restore(text));
+    let sent = if moderation.checks_reply() || extractor.is_some() {
+        // Hold the answer back until it is checked and extracted from.
+        let sent = bot.reply(&context, Vec::new()).await?;
+        if moderation.checks_reply() {
+            moderation.check(&bot, &sent.text).await?;
+        }
+        // The whole answer is still recorded if nothing is extracted.
+        let reply = restore(&sent.text);
+        let output = match &extractor {
+            Some(extractor) => extractor.extract(&reply),
+            None => Ok(reply),
+        };
+        if let Ok(output) = &output {
+            writer.write_all(output.as_bytes()).await?;
+            writer.flush().await?;
+        }
+        extracted = output.map(drop);
+        sent
+    } else if let Some(redactor) = restorer {
+        let mut writer = Restorer::new(&mut writer, redactor);
+        let sent = bot.reply(&context, &mut writer).await?;
+        writer.finish().await?;
+        sent
+    } else {
+        bot.reply(&context, &mut writer).await?
+    };
+    // Secrets restored in the answer are never logged.
+    let reply = restore(&sent.text);
+
+    bot.log(&Invocation {
+        started,
+        duration: started.elapsed().unwrap_or_default(),
+        path: conversation.path.clone(),
+        conversation: &context,
+        parameters: json!({
+            "model": model,
+            "max_tokens": bot.max_tokens,
+            "branch": cli.branch,
+            "index": cli.index,
+        }),
+        reply: &sent.text,
+        usage: sent.usage,
     });
 
-    Bot::default()
-        .reply_to_writer(&conversation, tokio::io::stdout())
-        .await?;
-    Ok(())
+    if let Some(path) = &cli.speak {
+        let audio = bot.speak(&sent.text, &cli.speech).await?;
+        speech::write_audio(path, &audio)?;
+    }
+
+    if cli.save {
+        if conversation.path.is_none() {
+            log::warn!("there is no conversation file to save to");
+        }
+
+        let node = match question {
+            Some(question) => Some(conversation.append(node, question)),
+            None => node,
+        };
+        conversation.append(node, Message::from_assistant(reply));
+        conversation.save()?;
+    }
+
+    if !sources.is_empty() && extractor.is_none() {
+        let mut citations = String::from("\n\nSources:\n");
+        for (number, source) in sources.iter().enumerate() {
+            citations.push_str(&format!("[{number}] {source}\n", number = number + 1));
+        }
+        writer.write_all(citations.as_bytes()).await?;
+        writer.flush().await?;
+    }
+    extracted
 }
 
 /// Determine whether a [`Role`] corresponds to a user.
@@ -326,7 +1707,19 @@ async fn main() -> anyhow::Result<()> {
 const fn is_user(role: &Role) -> bool {
     match role {
         Role::User => true,
-        Role::System | Role::Assistant => false,
+        Role::System | Role::Developer | Role::Assistant | Role::Tool => false,
+    }
+}
+
+/// Get a short, single-line version of a text for display.
+#[inline]
+fn snippet(text: &str) -> String {
+    const MAX_CHARS: usize = 80;
+
+    let text = text.split_whitespace().collect::<Vec<_>>().join(" ");
+    match text.char_indices().nth(MAX_CHARS) {
+        Some((index, _)) => format!("{text}…", text = &text[..index]),
+        None => text,
     }
 }
 
@@ -340,4 +1733,62 @@ mod tests {
     fn verify_cli() {
         Cli::command().debug_assert();
     }
+
+    #[test]
+    fn subcommands_redact() {
+        for args in [
+            [
+                "answer",
+                "--redact",
+                "batch",
+                "prompt.yml",
+                "--input",
+                "in.jsonl",
+            ],
+            [
+                "answer",
+                "batch",
+                "prompt.yml",
+                "--input",
+                "in.jsonl",
+                "--redact",
+            ],
+        ] {
+            let cli =
+                Cli::try_parse_from(args.into_iter().chain(["--output", "out.jsonl"])).unwrap();
+            assert!(matches!(cli.command, Some(Command::Batch(_))));
+            assert!(cli.conversation.is_none());
+            assert!(cli.redaction.redactor().is_some());
+        }
+    }
+
+    #[test]
+    fn newer_roles_and_fields_round_trip() {
+        let yaml = r#"messages:
+- role: developer
+  content: Answer with the weather tool.
+- content: Weather in Paris?
+- role: assistant
+  tool_calls:
+  - id: call_1
+    type: function
+    function:
+      name: weather
+      arguments: '{"city":"Paris"}'
+- role: tool
+  content: Sunny.
+  tool_call_id: call_1
+  options:
+    cache_control:
+      type: ephemeral
+"#;
+        let conversation = Conversation::from_reader(yaml.as_bytes()).unwrap();
+        assert_eq!(serde_yaml::to_string(&conversation).unwrap(), yaml);
+
+        let api = conversation.to_api().unwrap();
+        assert_eq!(api[0]["role"], "developer");
+        assert_eq!(api[2]["tool_calls"][0]["function"]["name"], "weather");
+        assert_eq!(api[3]["tool_call_id"], "call_1");
+        assert_eq!(api[3]["cache_control"]["type"], "ephemeral");
+    }
 }
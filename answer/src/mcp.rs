@@ -0,0 +1,705 @@
+//! Model Context Protocol (MCP) client.
+//!
+//! Conversation files can list MCP servers,
+//! started as a command speaking over its standard input and output,
+//! or reached at a local HTTP endpoint:
+//!
+//! ```yaml
+//! mcp:
+//!   - name: files
+//!     command: npx
+//!     args: [-y, "@modelcontextprotocol/server-filesystem", "."]
+//!   - name: tracker
+//!     url: http://localhost:3000/mcp
+//! messages:
+//!   - content: Which files in this directory mention a deadline?
+//! ```
+//!
+//! The tools of every server are offered to the model while replying,
+//! along with a tool reading the resources of the server, if it has any.
+//! Tool calls are made on behalf of the model until it replies with text.
+
+use std::collections::BTreeMap;
+use std::process::Stdio;
+
+use async_openai::error::OpenAIError;
+use async_openai::types::Usage;
+use serde::Deserialize;
+use serde::Serialize;
+use serde_json::json;
+use serde_json::Value;
+use thiserror::Error;
+use tokio::io::AsyncBufReadExt;
+use tokio::io::AsyncWrite;
+use tokio::io::AsyncWriteExt;
+use tokio::io::BufReader;
+use tokio::io::Lines;
+use tokio::process::Child;
+use tokio::process::ChildStdin;
+use tokio::process::ChildStdout;
+use tokio::process::Command;
+
+use crate::budget;
+use crate::exit::stream_error;
+use crate::Bot;
+use crate::BotError;
+use crate::Conversation;
+use crate::Reply;
+
+/// Version of the protocol requested from servers.
+const PROTOCOL_VERSION: &str = "2024-11-05";
+
+/// Maximum number of rounds of tool calls in a single reply.
+const MAX_ROUNDS: usize = 8;
+
+/// Separator between server and tool names in function names.
+const SEPARATOR: &str = "__";
+
+/// Name of the tool reading resources of a server.
+const READ_RESOURCE: &str = "read_resource";
+
+/// An MCP server listed in a [`Conversation`].
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Server {
+    /// Name of the [`Server`],
+    /// prefixed to the names of its tools.
+    pub name: String,
+    /// How to reach the [`Server`].
+    #[serde(flatten)]
+    pub transport: Transport,
+}
+
+/// How to reach a [`Server`].
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum Transport {
+    /// A command speaking over its standard input and output.
+    Stdio {
+        /// The command to run.
+        command: String,
+        /// Arguments of the command.
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        args: Vec<String>,
+        /// Environment variables set for the command.
+        #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+        env: BTreeMap<String, String>,
+    },
+    /// An HTTP endpoint.
+    Http {
+        /// URL of the endpoint.
+        url: String,
+    },
+}
+
+/// An error that came from an MCP [`Server`].
+#[derive(Debug, Error)]
+pub enum McpError {
+    #[error("could not start {command:?}: {source}")]
+    Spawn {
+        command: String,
+        source: std::io::Error,
+    },
+    #[error("could not talk to the server: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("could not parse a message from the server: {0}")]
+    Json(#[from] serde_json::Error),
+    #[error("could not reach the server: {0}")]
+    Http(#[from] reqwest::Error),
+    #[error("server closed the connection")]
+    Closed,
+    #[error("server returned error {code}: {message}")]
+    Rpc { code: i64, message: String },
+    #[error("no tool named {0:?}")]
+    UnknownTool(String),
+    #[error("model still calls tools after {0} rounds")]
+    TooManyRounds(usize),
+}
+
+/// A connection to a [`Server`].
+enum Connection {
+    /// A child process.
+    Stdio {
+        /// The process,
+        /// which is killed when dropped.
+        _child: Box<Child>,
+        /// Standard input of the process.
+        stdin: ChildStdin,
+        /// Lines of the standard output of the process.
+        stdout: Box<Lines<BufReader<ChildStdout>>>,
+    },
+    /// An HTTP endpoint.
+    Http {
+        /// The HTTP client.
+        client: reqwest::Client,
+        /// URL of the endpoint.
+        url: String,
+        /// Session identifier given by the server, if any.
+        session: Option<String>,
+    },
+}
+
+/// A client of an MCP [`Server`].
+pub struct McpClient {
+    /// The connection to the [`Server`].
+    connection: Connection,
+    /// Identifier of the next request.
+    next_id: u64,
+    /// Whether the [`Server`] has resources.
+    has_resources: bool,
+}
+
+impl McpClient {
+    /// Connect to a [`Server`] and initialize the session.
+    #[inline]
+    pub async fn connect(server: &Server) -> Result<Self, McpError> {
+        let connection = match &server.transport {
+            Transport::Stdio { command, args, env } => {
+                let mut child = Command::new(command)
+                    .args(args)
+                    .envs(env)
+                    .stdin(Stdio::piped())
+                    .stdout(Stdio::piped())
+                    .kill_on_drop(true)
+                    .spawn()
+                    .map_err(|source| McpError::Spawn {
+                        command: command.clone(),
+                        source,
+                    })?;
+                let stdin = child.stdin.take().ok_or(McpError::Closed)?;
+                let stdout = child.stdout.take().ok_or(McpError::Closed)?;
+                Connection::Stdio {
+                    _child: Box::new(child),
+                    stdin,
+                    stdout: Box::new(BufReader::new(stdout).lines()),
+                }
+            }
+            Transport::Http { url } => Connection::Http {
+                client: reqwest::Client::new(),
+                url: url.clone(),
+                session: None,
+            },
+        };
+
+        let mut client = Self {
+            connection,
+            next_id: 1,
+            has_resources: false,
+        };
+        let result = client
+            .request(
+                "initialize",
+                json!({
+                    "protocolVersion": PROTOCOL_VERSION,
+                    "capabilities": {},
+                    "clientInfo": {
+                        "name": env!("CARGO_PKG_NAME"),
+                        "version": env!("CARGO_PKG_VERSION"),
+                    },
+                }),
+            )
+            .await?;
+        client.has_resources = result["capabilities"].get("resources").is_some();
+        client
+            .send(&json!({
+                "jsonrpc": "2.0",
+                "method": "notifications/initialized",
+            }))
+            .await?;
+        Ok(client)
+    }
+
+    /// List the tools of the [`Server`],
+    /// as `(name, description, input schema)`.
+    #[inline]
+    pub async fn tools(&mut self) -> Result<Vec<(String, String, Value)>, McpError> {
+        let result = self.request("tools/list", json!({})).await?;
+        Ok(result["tools"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .filter_map(|tool| {
+                Some((
+                    tool["name"].as_str()?.to_owned(),
+                    tool["description"].as_str().unwrap_or_default().to_owned(),
+                    tool.get("inputSchema")
+                        .cloned()
+                        .unwrap_or_else(|| json!({ "type": "object" })),
+                ))
+            })
+            .collect())
+    }
+
+    /// List the resources of the [`Server`],
+    /// as `(URI, name)`.
+    #[inline]
+    pub async fn resources(&mut self) -> Result<Vec<(String, String)>, McpError> {
+        if !self.has_resources {
+            return Ok(Vec::new());
+        }
+
+        let result = self.request("resources/list", json!({})).await?;
+        Ok(result["resources"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .filter_map(|resource| {
+                Some((
+                    resource["uri"].as_str()?.to_owned(),
+                    resource["name"].as_str().unwrap_or_default().to_owned(),
+                ))
+            })
+            .collect())
+    }
+
+    /// Call a tool of the [`Server`],
+    /// returning its text output.
+    #[inline]
+    pub async fn call_tool(&mut self, name: &str, arguments: Value) -> Result<String, McpError> {
+        let result = self
+            .request(
+                "tools/call",
+                json!({ "name": name, "arguments": arguments }),
+            )
+            .await?;
+        let text = texts(&result["content"]);
+        if result["isError"].as_bool().unwrap_or(false) {
+            Ok(format!("error: {text}"))
+        } else {
+            Ok(text)
+        }
+    }
+
+    /// Read a resource of the [`Server`],
+    /// returning its text.
+    #[inline]
+    pub async fn read_resource(&mut self, uri: &str) -> Result<String, McpError> {
+        let result = self
+            .request("resources/read", json!({ "uri": uri }))
+            .await?;
+        Ok(texts(&result["contents"]))
+    }
+
+    /// Send a request and wait for its result.
+    #[inline]
+    async fn request(&mut self, method: &str, params: Value) -> Result<Value, McpError> {
+        let id = self.next_id;
+        self.next_id += 1;
+
+        let response = self
+            .send(&json!({
+                "jsonrpc": "2.0",
+                "id": id,
+                "method": method,
+                "params": params,
+            }))
+            .await?;
+        let response = match response {
+            Some(response) => response,
+            None => self.receive(id).await?,
+        };
+
+        if let Some(error) = response.get("error") {
+            return Err(McpError::Rpc {
+                code: error["code"].as_i64().unwrap_or_default(),
+                message: error["message"].as_str().unwrap_or_default().to_owned(),
+            });
+        }
+        Ok(response["result"].clone())
+    }
+
+    /// Send a message.
+    ///
+    /// Over HTTP,
+    /// the response to a request comes back right away and is returned.
+    #[inline]
+    async fn send(&mut self, message: &Value) -> Result<Option<Value>, McpError> {
+        match &mut self.connection {
+            Connection::Stdio { stdin, .. } => {
+                let mut line = serde_json::to_vec(message)?;
+                line.push(b'\n');
+                stdin.write_all(&line).await?;
+                stdin.flush().await?;
+                Ok(None)
+            }
+            Connection::Http {
+                client,
+                url,
+                session,
+            } => {
+                let mut request = client
+                    .post(url.as_str())
+                    .header("Accept", "application/json, text/event-stream")
+                    .json(message);
+                if let Some(session) = session {
+                    request = request.header("Mcp-Session-Id", session.as_str());
+                }
+                let response = request.send().await?.error_for_status()?;
+                if let Some(id) = response
+                    .headers()
+                    .get("Mcp-Session-Id")
+                    .and_then(|id| id.to_str().ok())
+                {
+                    *session = Some(id.to_owned());
+                }
+
+                let Some(id) = message.get("id") else {
+                    return Ok(None);
+                };
+                let is_stream = response
+                    .headers()
+                    .get(reqwest::header::CONTENT_TYPE)
+                    .and_then(|kind| kind.to_str().ok())
+                    .is_some_and(|kind| kind.starts_with("text/event-stream"));
+                let body = response.text().await?;
+                if !is_stream {
+                    return Ok(Some(serde_json::from_str(&body)?));
+                }
+
+                // Find the response among the events of the stream.
+                body.lines()
+                    .filter_map(|line| line.strip_prefix("data:"))
+                    .filter_map(|data| serde_json::from_str::<Value>(data.trim()).ok())
+                    .find(|event| event.get("id") == Some(id))
+                    .map(Some)
+                    .ok_or(McpError::Closed)
+            }
+        }
+    }
+
+    /// Receive messages over standard output until the response to a
+    /// request arrives.
+    #[inline]
+    async fn receive(&mut self, id: u64) -> Result<Value, McpError> {
+        loop {
+            let line = match &mut self.connection {
+                Connection::Stdio { stdout, .. } => stdout.next_line().await?,
+                Connection::Http { .. } => None,
+            };
+            let Some(line) = line else {
+                return Err(McpError::Closed);
+            };
+            if line.trim().is_empty() {
+                continue;
+            }
+
+            let message: Value = serde_json::from_str(&line)?;
+            match (message.get("id"), message.get("method")) {
+                // A request from the server.
+                (Some(request), Some(method)) => {
+                    let reply = if method == "ping" {
+                        json!({ "jsonrpc": "2.0", "id": request, "result": {} })
+                    } else {
+                        json!({
+                            "jsonrpc": "2.0",
+                            "id": request,
+                            "error": { "code": -32601, "message": "method not found" },
+                        })
+                    };
+                    self.send(&reply).await?;
+                }
+                (Some(response), None) if response.as_u64() == Some(id) => return Ok(message),
+                // Notifications and stray responses.
+                _ => log::debug!("ignoring {message}"),
+            }
+        }
+    }
+}
+
+/// Join the text parts of MCP content.
+#[inline]
+fn texts(content: &Value) -> String {
+    content
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(|part| part["text"].as_str())
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// What a function offered to the model stands for.
+enum Function {
+    /// A tool of a [`Server`].
+    Tool(String),
+    /// Reading the resources of a [`Server`].
+    ReadResource,
+}
+
+/// Tools of every [`Server`] of a [`Conversation`],
+/// offered to the model as functions.
+pub struct Toolbox {
+    /// Clients of the [`Server`]s.
+    clients: Vec<McpClient>,
+    /// Functions offered to the model,
+    /// along with the client they belong to.
+    functions: BTreeMap<String, (usize, Function)>,
+    /// Definitions of the functions,
+    /// as expected by the chat completion API.
+    definitions: Vec<Value>,
+}
+
+impl Toolbox {
+    /// Connect to [`Server`]s and list their tools and resources.
+    #[inline]
+    pub async fn connect(servers: &[Server]) -> Result<Self, McpError> {
+        let mut toolbox = Self {
+            clients: Vec::new(),
+            functions: BTreeMap::new(),
+            definitions: Vec::new(),
+        };
+
+        for (index, server) in servers.iter().enumerate() {
+            let mut client = McpClient::connect(server).await?;
+            for (tool, description, schema) in client.tools().await? {
+                let name = function_name(&server.name, &tool);
+                toolbox.define(&name, &description, schema);
+                toolbox
+                    .functions
+                    .insert(name, (index, Function::Tool(tool)));
+            }
+
+            let resources = client.resources().await?;
+            if !resources.is_empty() {
+                let name = function_name(&server.name, READ_RESOURCE);
+                let listing = resources
+                    .iter()
+                    .map(|(uri, name)| format!("- {uri} ({name})"))
+                    .collect::<Vec<_>>()
+                    .join("\n");
+                toolbox.define(
+                    &name,
+                    &format!(
+                        "Read a resource of {server}. Resources:\n{listing}",
+                        server = server.name
+                    ),
+                    json!({
+                        "type": "object",
+                        "properties": { "uri": { "type": "string" } },
+                        "required": ["uri"],
+                    }),
+                );
+                toolbox
+                    .functions
+                    .insert(name, (index, Function::ReadResource));
+            }
+            toolbox.clients.push(client);
+        }
+        Ok(toolbox)
+    }
+
+    /// Add the definition of a function.
+    #[inline]
+    fn define(&mut self, name: &str, description: &str, parameters: Value) {
+        self.definitions.push(json!({
+            "type": "function",
+            "function": {
+                "name": name,
+                "description": description,
+                "parameters": parameters,
+            },
+        }));
+    }
+
+    /// Call a function with arguments encoded as JSON.
+    #[inline]
+    pub async fn call(&mut self, name: &str, arguments: &str) -> Result<String, McpError> {
+        let (index, function) = self
+            .functions
+            .get(name)
+            .ok_or_else(|| McpError::UnknownTool(name.to_owned()))?;
+        let arguments: Value = if arguments.trim().is_empty() {
+            json!({})
+        } else {
+            serde_json::from_str(arguments)?
+        };
+
+        let client = &mut self.clients[*index];
+        match function {
+            Function::Tool(tool) => client.call_tool(tool, arguments).await,
+            Function::ReadResource => {
+                let uri = arguments["uri"].as_str().unwrap_or_default();
+                client.read_resource(uri).await
+            }
+        }
+    }
+}
+
+/// Get the name of the function standing for a tool of a [`Server`].
+///
+/// Function names may only hold letters, digits, underscores and dashes,
+/// and are at most 64 characters long.
+#[inline]
+fn function_name(server: &str, tool: &str) -> String {
+    format!("{server}{SEPARATOR}{tool}")
+        .chars()
+        .map(|character| {
+            if character.is_ascii_alphanumeric() || character == '_' || character == '-' {
+                character
+            } else {
+                '_'
+            }
+        })
+        .take(64)
+        .collect()
+}
+
+impl Bot {
+    /// Reply, in the context of a [`Conversation`] with MCP [`Server`]s,
+    /// to the given [`AsyncWrite`]r,
+    /// calling tools on behalf of the model until it replies with text.
+    ///
+    /// The requests are made by hand,
+    /// as [`async_openai::Client`] does not support tools.
+    #[inline]
+    pub async fn reply_with_tools<W>(
+        &self,
+        conversation: &Conversation,
+        mut writer: W,
+    ) -> Result<Reply, BotError>
+    where
+        W: AsyncWrite + Send + Unpin,
+    {
+        let mut usage: Option<Usage> = None;
+        let mut toolbox = Toolbox::connect(&conversation.mcp).await?;
+        let client = self.client()?;
+        let mut messages = conversation.to_api()?;
+
+        let model = self.model(conversation);
+        for _ in 0..MAX_ROUNDS {
+            let prompt_tokens = budget::count_tokens(&json!(messages).to_string());
+            let (max_tokens, reservation) =
+                self.check_budget(model, prompt_tokens, self.max_tokens)?;
+            let response = reqwest::Client::new()
+                .post(format!("{base}/chat/completions", base = client.api_base()))
+                .bearer_auth(client.api_key())
+                .json(&json!({
+                    "model": model,
+                    "temperature": 0.0,
+                    "max_tokens": max_tokens,
+                    "messages": messages,
+                    "tools": toolbox.definitions,
+                }))
+                .send()
+                .await
+                .map_err(OpenAIError::from)?;
+            let status = response.status();
+            if !status.is_success() {
+                let body = response.bytes().await.map_err(OpenAIError::from)?;
+                return Err(stream_error(status, &body));
+            }
+            let response: Value = response.json().await.map_err(OpenAIError::from)?;
+
+            let round = serde_json::from_value::<Usage>(response["usage"].clone()).ok();
+            reservation.settle(
+                &response["choices"][0]["message"].to_string(),
+                round.as_ref(),
+            );
+            if let Some(round) = round {
+                usage = Some(match usage {
+                    Some(total) => Usage {
+                        prompt_tokens: total.prompt_tokens + round.prompt_tokens,
+                        completion_tokens: total.completion_tokens + round.completion_tokens,
+                        total_tokens: total.total_tokens + round.total_tokens,
+                    },
+                    None => round,
+                });
+            }
+
+            let message = response["choices"][0]["message"].clone();
+            let calls = message["tool_calls"]
+                .as_array()
+                .cloned()
+                .unwrap_or_default();
+            if calls.is_empty() {
+                let text = message["content"].as_str().unwrap_or_default().to_owned();
+                writer.write_all(text.as_bytes()).await?;
+                writer.flush().await?;
+                return Ok(Reply { text, usage });
+            }
+
+            messages.push(message);
+            for call in calls {
+                let name = call["function"]["name"].as_str().unwrap_or_default();
+                let arguments = call["function"]["arguments"].as_str().unwrap_or_default();
+                log::info!("calling {name}({arguments})");
+                let output = toolbox.call(name, arguments).await.unwrap_or_else(|err| {
+                    log::warn!("could not call {name}: {err}");
+                    format!("error: {err}")
+                });
+                messages.push(json!({
+                    "role": "tool",
+                    "tool_call_id": call["id"],
+                    "content": self.redact(&output),
+                }));
+            }
+        }
+        Err(McpError::TooManyRounds(MAX_ROUNDS).into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A tiny MCP server with an `echo` tool.
+    #[cfg(unix)]
+    const SERVER: &str = r#"
+while IFS= read -r line; do
+  id=$(printf '%s' "$line" | sed -n 's/.*"id":\([0-9]*\).*/\1/p')
+  case "$line" in
+    *'"initialize"'*)
+      printf '{"jsonrpc":"2.0","id":%s,"result":{"protocolVersion":"2024-11-05","capabilities":{"tools":{}},"serverInfo":{"name":"test","version":"0"}}}\n' "$id" ;;
+    *'"tools/list"'*)
+      printf '{"jsonrpc":"2.0","method":"notifications/message","params":{}}\n'
+      printf '{"jsonrpc":"2.0","id":%s,"result":{"tools":[{"name":"echo","description":"Echo a text.","inputSchema":{"type":"object"}}]}}\n' "$id" ;;
+    *'"tools/call"'*)
+      printf '{"jsonrpc":"2.0","id":%s,"result":{"content":[{"type":"text","text":"hello"}]}}\n' "$id" ;;
+  esac
+done
+"#;
+
+    #[test]
+    fn servers_are_listed_in_conversations() {
+        let conversation: Conversation = serde_yaml::from_str(
+            "mcp:\n\
+             - name: files\n  command: mcp-files\n  args: [.]\n\
+             - name: tracker\n  url: http://localhost:3000/mcp\n",
+        )
+        .unwrap();
+        assert!(matches!(
+            &conversation.mcp[0].transport,
+            Transport::Stdio { args, .. } if args == &["."]
+        ));
+        assert!(matches!(
+            &conversation.mcp[1].transport,
+            Transport::Http { url } if url == "http://localhost:3000/mcp"
+        ));
+        assert_eq!(
+            function_name("my server", "read.file"),
+            "my_server__read_file"
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn tools_are_called_over_stdio() {
+        let server = Server {
+            name: "test".to_owned(),
+            transport: Transport::Stdio {
+                command: "sh".to_owned(),
+                args: vec!["-c".to_owned(), SERVER.to_owned()],
+                env: BTreeMap::new(),
+            },
+        };
+
+        tokio::runtime::Runtime::new().unwrap().block_on(async {
+            let mut toolbox = Toolbox::connect(&[server]).await.unwrap();
+            assert_eq!(toolbox.definitions[0]["function"]["name"], "test__echo");
+            assert_eq!(toolbox.call("test__echo", "{}").await.unwrap(), "hello");
+            assert!(matches!(
+                toolbox.call("test__missing", "{}").await,
+                Err(McpError::UnknownTool(_))
+            ));
+        });
+    }
+}
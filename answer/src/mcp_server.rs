@@ -0,0 +1,373 @@
+//! Publishing prompts as a Model Context Protocol (MCP) server.
+//!
+//! Each conversation file in a directory
+//! (by default, the library of named prompts)
+//! is published over the standard input and output both as a tool,
+//! which takes a user message and returns the reply of the model,
+//! and as a prompt,
+//! which returns the messages of the conversation followed by the user
+//! message.
+
+use std::fs;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use clap::Args;
+use serde_json::json;
+use serde_json::Value;
+use tokio::io::AsyncBufReadExt;
+use tokio::io::AsyncWriteExt;
+use tokio::io::BufReader;
+use tokio::io::Stdout;
+use tokio::sync::Mutex;
+
+use crate::history::Invocation;
+use crate::parse_conversation;
+use crate::prompts;
+use crate::Bot;
+use crate::CliError;
+use crate::Conversation;
+use crate::Message;
+use crate::Role;
+
+/// Version of the protocol spoken.
+const PROTOCOL_VERSION: &str = "2024-11-05";
+
+/// Name of the argument holding the user message.
+const ARGUMENT: &str = "message";
+
+/// Publish conversation files as an MCP server over the standard input and
+/// output.
+#[derive(Debug, Args)]
+pub struct McpServeCommand {
+    /// Directory of conversation files to publish.
+    ///
+    /// If not given,
+    /// the library of named prompts is published instead.
+    directory: Option<PathBuf>,
+}
+
+impl McpServeCommand {
+    /// Run this [`McpServeCommand`].
+    #[inline]
+    pub async fn run(self, bot: Bot) -> Result<(), CliError> {
+        let server = Arc::new(Publisher {
+            directory: self.directory,
+            bot,
+        });
+        let stdout = Arc::new(Mutex::new(tokio::io::stdout()));
+
+        let mut lines = BufReader::new(tokio::io::stdin()).lines();
+        while let Some(line) = lines.next_line().await? {
+            if line.trim().is_empty() {
+                continue;
+            }
+            let message: Value = match serde_json::from_str(&line) {
+                Ok(message) => message,
+                Err(err) => {
+                    let error = json!({
+                        "jsonrpc": "2.0",
+                        "id": null,
+                        "error": { "code": -32700, "message": err.to_string() },
+                    });
+                    respond(&stdout, &error).await?;
+                    continue;
+                }
+            };
+
+            // Replies take a while,
+            // so requests are handled concurrently.
+            let server = Arc::clone(&server);
+            let stdout = Arc::clone(&stdout);
+            tokio::spawn(async move {
+                if let Some(response) = server.handle(message).await {
+                    if let Err(err) = respond(&stdout, &response).await {
+                        log::warn!("could not respond: {err}");
+                    }
+                }
+            });
+        }
+        Ok(())
+    }
+}
+
+/// Write a message to the standard output,
+/// one message per line.
+#[inline]
+async fn respond(stdout: &Mutex<Stdout>, message: &Value) -> Result<(), CliError> {
+    let mut line = serde_json::to_vec(message)?;
+    line.push(b'\n');
+
+    let mut stdout = stdout.lock().await;
+    stdout.write_all(&line).await?;
+    stdout.flush().await?;
+    Ok(())
+}
+
+/// Publishes conversation files.
+#[derive(Debug)]
+struct Publisher {
+    /// Directory of conversation files,
+    /// or [`None`] for the library of named prompts.
+    directory: Option<PathBuf>,
+    /// The [`Bot`] replying to tool calls.
+    bot: Bot,
+}
+
+impl Publisher {
+    /// List published conversations,
+    /// as names and what [`parse_conversation`] takes to load them.
+    ///
+    /// Files are listed anew on every request,
+    /// so that changes are picked up without restarting.
+    #[inline]
+    fn entries(&self) -> Vec<(String, String)> {
+        let mut entries: Vec<_> = match &self.directory {
+            Some(directory) => fs::read_dir(directory)
+                .into_iter()
+                .flatten()
+                .filter_map(Result::ok)
+                .map(|entry| entry.path())
+                .filter_map(|path| {
+                    let name = prompts::prompt_name(&path)?;
+                    Some((name, path.to_string_lossy().into_owned()))
+                })
+                .collect(),
+            None => prompts::list()
+                .into_iter()
+                .map(|prompt| {
+                    let source = format!("@{name}", name = prompt.name);
+                    (prompt.name, source)
+                })
+                .collect(),
+        };
+        entries.sort();
+        entries
+    }
+
+    /// Load a published [`Conversation`] by name.
+    #[inline]
+    fn load(&self, name: &str) -> Result<Conversation, String> {
+        let (_, source) = self
+            .entries()
+            .into_iter()
+            .find(|(entry, _)| entry == name)
+            .ok_or_else(|| format!("unknown prompt {name:?}"))?;
+        parse_conversation(&source).map_err(|err| err.to_string())
+    }
+
+    /// Handle a message,
+    /// returning the response, if any.
+    #[inline]
+    async fn handle(&self, message: Value) -> Option<Value> {
+        // Notifications have no identifier and get no response.
+        let id = message.get("id")?.clone();
+        let params = &message["params"];
+        let result = match message["method"].as_str().unwrap_or_default() {
+            "initialize" => Ok(json!({
+                "protocolVersion": PROTOCOL_VERSION,
+                "capabilities": { "tools": {}, "prompts": {} },
+                "serverInfo": {
+                    "name": env!("CARGO_PKG_NAME"),
+                    "version": env!("CARGO_PKG_VERSION"),
+                },
+            })),
+            "ping" => Ok(json!({})),
+            "tools/list" => Ok(json!({ "tools": self.tools() })),
+            "tools/call" => Ok(self.call(params).await),
+            "prompts/list" => Ok(json!({ "prompts": self.prompts() })),
+            "prompts/get" => self.prompt(params),
+            method => Err((-32601, format!("unknown method {method:?}"))),
+        };
+
+        Some(match result {
+            Ok(result) => json!({ "jsonrpc": "2.0", "id": id, "result": result }),
+            Err((code, message)) => json!({
+                "jsonrpc": "2.0",
+                "id": id,
+                "error": { "code": code, "message": message },
+            }),
+        })
+    }
+
+    /// List published conversations as tools.
+    #[inline]
+    fn tools(&self) -> Vec<Value> {
+        self.entries()
+            .into_iter()
+            .map(|(name, source)| {
+                json!({
+                    "name": name,
+                    "description": describe(&name, parse_conversation(&source).ok().as_ref()),
+                    "inputSchema": {
+                        "type": "object",
+                        "properties": {
+                            ARGUMENT: {
+                                "type": "string",
+                                "description": "The user message to reply to.",
+                            },
+                        },
+                        "required": [ARGUMENT],
+                    },
+                })
+            })
+            .collect()
+    }
+
+    /// Reply to a user message in the context of a published
+    /// [`Conversation`].
+    ///
+    /// Failures are reported as tool errors.
+    #[inline]
+    async fn call(&self, params: &Value) -> Value {
+        let name = params["name"].as_str().unwrap_or_default();
+        let message = params["arguments"][ARGUMENT].as_str().unwrap_or_default();
+
+        let reply = match self.load(name) {
+            Ok(mut conversation) => {
+                conversation.push(Message::from_user(message));
+                let started = SystemTime::now();
+                match self.bot.reply(&conversation, Vec::new()).await {
+                    Ok(reply) => {
+                        self.bot.log(&Invocation {
+                            started,
+                            duration: started.elapsed().unwrap_or_default(),
+                            path: conversation.path.clone(),
+                            conversation: &conversation,
+                            parameters: json!({
+                                "model": self.bot.model(&conversation),
+                                "max_tokens": self.bot.max_tokens,
+                                "tool": name,
+                            }),
+                            reply: &reply.text,
+                            usage: reply.usage,
+                        });
+                        Ok(reply.text)
+                    }
+                    Err(err) => Err(err.to_string()),
+                }
+            }
+            Err(err) => Err(err),
+        };
+        match reply {
+            Ok(reply) => json!({ "content": [{ "type": "text", "text": reply }] }),
+            Err(err) => json!({
+                "content": [{ "type": "text", "text": err }],
+                "isError": true,
+            }),
+        }
+    }
+
+    /// List published conversations as prompts.
+    #[inline]
+    fn prompts(&self) -> Vec<Value> {
+        self.entries()
+            .into_iter()
+            .map(|(name, source)| {
+                json!({
+                    "name": name,
+                    "description": describe(&name, parse_conversation(&source).ok().as_ref()),
+                    "arguments": [{
+                        "name": ARGUMENT,
+                        "description": "The user message to reply to.",
+                        "required": false,
+                    }],
+                })
+            })
+            .collect()
+    }
+
+    /// Get the messages of a published [`Conversation`],
+    /// followed by the user message, if any.
+    ///
+    /// Prompts only have user and assistant messages,
+    /// so system messages are sent as user messages.
+    #[inline]
+    fn prompt(&self, params: &Value) -> Result<Value, (i64, String)> {
+        let name = params["name"].as_str().unwrap_or_default();
+        let mut conversation = self.load(name).map_err(|err| (-32602, err))?;
+        let description = describe(name, Some(&conversation));
+        if let Some(message) = params["arguments"][ARGUMENT].as_str() {
+            conversation.push(Message::from_user(message));
+        }
+
+        let messages: Vec<_> = conversation
+            .messages
+            .iter()
+            .map(|message| {
+                let role = match message.role {
+                    Role::Assistant => "assistant",
+                    _ => "user",
+                };
+                json!({
+                    "role": role,
+                    "content": { "type": "text", "text": message.content.to_string() },
+                })
+            })
+            .collect();
+        Ok(json!({
+            "description": description,
+            "messages": messages,
+        }))
+    }
+}
+
+/// Describe a published [`Conversation`] by its first system message.
+#[inline]
+fn describe(name: &str, conversation: Option<&Conversation>) -> String {
+    conversation
+        .and_then(|conversation| {
+            conversation
+                .messages
+                .iter()
+                .find(|message| matches!(message.role, Role::System | Role::Developer))
+        })
+        .map_or_else(
+            || format!("Reply in the context of the {name} prompt."),
+            |message| message.content.to_string(),
+        )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn library_is_published_as_tools_and_prompts() {
+        let publisher = Publisher {
+            directory: None,
+            bot: Bot::default(),
+        };
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+
+        let response = runtime
+            .block_on(publisher.handle(json!({
+                "jsonrpc": "2.0",
+                "id": 1,
+                "method": "tools/list",
+            })))
+            .unwrap();
+        let tools = response["result"]["tools"].as_array().unwrap();
+        assert!(tools
+            .iter()
+            .any(|tool| tool["name"] == "act-as-a-linux-terminal"));
+
+        let response = runtime
+            .block_on(publisher.handle(json!({
+                "jsonrpc": "2.0",
+                "id": 2,
+                "method": "prompts/get",
+                "params": {
+                    "name": "act-as-a-linux-terminal",
+                    "arguments": { "message": "pwd" },
+                },
+            })))
+            .unwrap();
+        let messages = response["result"]["messages"].as_array().unwrap();
+        assert_eq!(messages.last().unwrap()["content"]["text"], "pwd");
+
+        let notification = json!({ "jsonrpc": "2.0", "method": "notifications/initialized" });
+        assert!(runtime.block_on(publisher.handle(notification)).is_none());
+    }
+}
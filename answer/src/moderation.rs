@@ -0,0 +1,224 @@
+//! Moderation of questions and answers.
+//!
+//! Texts can be checked against the moderation endpoint,
+//! a local [`Policy`] of keywords and patterns,
+//! or both,
+//! before being sent (or, for answers, shown).
+//!
+//! A [`Policy`] file looks like this:
+//!
+//! ```yaml
+//! keywords: [password, salary] # matched as whole words, ignoring case
+//! patterns: ['\b\d{3}-\d{2}-\d{4}\b'] # regular expressions
+//! ```
+
+use std::fs::File;
+use std::path::Path;
+use std::path::PathBuf;
+
+use async_openai::types::CreateModerationRequestArgs;
+use async_openai::types::ModerationInput;
+use clap::Args;
+use regex::Regex;
+use serde::Deserialize;
+use serde_json::Value;
+
+use crate::Bot;
+use crate::BotError;
+use crate::CliError;
+
+/// A local content policy.
+#[derive(Clone, Debug)]
+pub struct Policy {
+    /// Rules of this [`Policy`],
+    /// along with the keyword or pattern they came from.
+    rules: Vec<(String, Regex)>,
+}
+
+/// A [`Policy`] as written in a file.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct PolicyFile {
+    /// Words that are not allowed,
+    /// matched as whole words and ignoring case.
+    #[serde(default)]
+    keywords: Vec<String>,
+    /// Regular expressions that must not match.
+    #[serde(default)]
+    patterns: Vec<String>,
+}
+
+impl Policy {
+    /// Create a [`Policy`] from keywords and patterns.
+    #[inline]
+    fn new(keywords: Vec<String>, patterns: Vec<String>) -> Result<Self, regex::Error> {
+        let keywords = keywords.into_iter().map(|keyword| {
+            let regex = Regex::new(&format!(r"(?i)\b{}\b", regex::escape(&keyword)));
+            (keyword, regex)
+        });
+        let patterns = patterns.into_iter().map(|pattern| {
+            let regex = Regex::new(&pattern);
+            (pattern, regex)
+        });
+        let rules = keywords
+            .chain(patterns)
+            .map(|(rule, regex)| Ok((rule, regex?)))
+            .collect::<Result<_, _>>()?;
+        Ok(Self { rules })
+    }
+
+    /// Get the keywords and patterns of this [`Policy`] found in a text.
+    #[inline]
+    pub fn violations(&self, text: &str) -> Vec<String> {
+        self.rules
+            .iter()
+            .filter(|(_, regex)| regex.is_match(text))
+            .map(|(rule, _)| rule.clone())
+            .collect()
+    }
+}
+
+/// Get a [`Policy`] from a file [`Path`] by parsing.
+#[inline]
+fn parse_policy(path: &Path) -> Result<Policy, CliError> {
+    let PolicyFile { keywords, patterns } = serde_yaml::from_reader(File::open(path)?)?;
+    Ok(Policy::new(keywords, patterns)?)
+}
+
+/// Options for moderating questions and answers.
+#[derive(Debug, Args)]
+pub struct ModerationOptions {
+    /// Check the question with the moderation endpoint before sending it.
+    #[arg(long)]
+    moderate: bool,
+
+    /// Path to a local policy YAML file of forbidden keywords and patterns
+    /// to check the question against before sending it.
+    #[arg(long, value_name = "FILE")]
+    policy: Option<PathBuf>,
+
+    /// Also check the answer before showing it.
+    #[arg(long)]
+    moderate_reply: bool,
+}
+
+impl ModerationOptions {
+    /// Get the [`Moderation`] of these options,
+    /// parsing the [`Policy`] file if any.
+    #[inline]
+    pub fn moderation(&self) -> Result<Moderation, CliError> {
+        Ok(Moderation {
+            moderate: self.moderate,
+            policy: self.policy.as_deref().map(parse_policy).transpose()?,
+            moderate_reply: self.moderate_reply,
+        })
+    }
+}
+
+/// Checks of questions and answers.
+#[derive(Debug)]
+pub struct Moderation {
+    /// Whether texts are checked with the moderation endpoint.
+    moderate: bool,
+    /// The local [`Policy`] texts are checked against, if any.
+    policy: Option<Policy>,
+    /// Whether answers are checked too.
+    moderate_reply: bool,
+}
+
+impl Moderation {
+    /// Determine whether any check is enabled.
+    #[inline]
+    pub const fn is_enabled(&self) -> bool {
+        self.moderate || self.policy.is_some()
+    }
+
+    /// Determine whether answers are checked.
+    #[inline]
+    pub const fn checks_reply(&self) -> bool {
+        self.moderate_reply && self.is_enabled()
+    }
+
+    /// Check a text,
+    /// failing with [`BotError::Flagged`] if it is not allowed.
+    ///
+    /// The local [`Policy`] is checked first,
+    /// so that flagged texts are never sent.
+    #[inline]
+    pub async fn check(&self, bot: &Bot, text: &str) -> Result<(), BotError> {
+        if let Some(policy) = &self.policy {
+            let violations = policy.violations(text);
+            if !violations.is_empty() {
+                return Err(BotError::Flagged(violations));
+            }
+        }
+
+        if self.moderate {
+            let categories = bot.moderate(text).await?;
+            if !categories.is_empty() {
+                return Err(BotError::Flagged(categories));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Bot {
+    /// Check a text with the moderation endpoint.
+    ///
+    /// Returns the categories the text was flagged for, if any.
+    #[inline]
+    pub async fn moderate(&self, text: &str) -> Result<Vec<String>, BotError> {
+        let response = self
+            .client()?
+            .moderations()
+            .create(
+                CreateModerationRequestArgs::default()
+                    .input(ModerationInput::String(self.redact(text).into_owned()))
+                    .build()?,
+            )
+            .await?;
+        log::debug!("{response:?}");
+
+        let mut categories = Vec::new();
+        for result in response.results.into_iter().filter(|result| result.flagged) {
+            let before = categories.len();
+            if let Ok(Value::Object(flags)) = serde_json::to_value(result.categories) {
+                categories.extend(
+                    flags
+                        .into_iter()
+                        .filter(|(_, flag)| flag == &Value::Bool(true))
+                        .map(|(category, _)| category),
+                );
+            }
+            if categories.len() == before {
+                // Flagged without any known category.
+                categories.push("unknown".to_owned());
+            }
+        }
+        Ok(categories)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn policies_match_keywords_and_patterns() {
+        let policy = Policy::new(
+            vec!["salary".to_owned()],
+            vec![r"\b\d{3}-\d{2}-\d{4}\b".to_owned()],
+        )
+        .unwrap();
+        assert_eq!(policy.violations("What is my Salary?"), ["salary"]);
+        assert!(policy
+            .violations("What are the salaryman hours?")
+            .is_empty());
+        assert_eq!(
+            policy.violations("My SSN is 123-45-6789."),
+            [r"\b\d{3}-\d{2}-\d{4}\b"]
+        );
+        assert!(Policy::new(Vec::new(), vec!["(".to_owned()]).is_err());
+    }
+}
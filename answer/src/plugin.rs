@@ -0,0 +1,261 @@
+//! External subcommands.
+//!
+//! Like `git`,
+//! `answer foo` runs an `answer-foo` executable found on the `PATH`,
+//! passing it the remaining arguments,
+//! as long as `foo` is neither a subcommand nor an existing file.
+//! Global options, such as `--profile` or `-v`, can come before `foo`.
+//! The plugin gets the resolved configuration in environment variables:
+//!
+//! - `ANSWER_BIN`: the `answer` executable, to call back,
+//! - `ANSWER_VERSION`: the version of `answer`,
+//! - `ANSWER_MODEL`: the model that would answer the conversation, if any,
+//! - `ANSWER_PROFILE`: the profile spent from,
+//!   `ANSWER_MAX_TOKENS`: the longest answer asked for, if any,
+//!   and `ANSWER_BUDGET_DAILY`, `ANSWER_BUDGET_MONTHLY` and
+//!   `ANSWER_BUDGET_MAX_TOKENS`: the budget of the profile, if any,
+//! - `ANSWER_REDACT`, `ANSWER_UNREDACT` (`1` if enabled) and
+//!   `ANSWER_REDACT_PATTERNS` (one per line): the redaction settings,
+//! - `ANSWER_CONFIG_DIR` and `ANSWER_DATA_DIR`: where configuration and
+//!   data are kept,
+//! - `ANSWER_CONVERSATION`: the conversation named by the first argument
+//!   that is a YAML file or `@name` of a prompt, as JSON,
+//!   and `ANSWER_CONVERSATION_PATH`: its file, if any
+//!   (both are left unset if it cannot be parsed).
+
+use std::env;
+use std::ffi::OsString;
+use std::path::Path;
+use std::path::PathBuf;
+
+use clap::CommandFactory;
+use clap::Parser;
+use tokio::process::Command;
+
+use crate::budget::Limits;
+use crate::budget::DEFAULT_PROFILE;
+use crate::parse_conversation;
+use crate::Bot;
+use crate::Cli;
+use crate::CliError;
+use crate::CHAT_MODEL;
+
+/// Prefix of plugin executables.
+const PREFIX: &str = "answer-";
+
+/// An external subcommand.
+#[derive(Debug)]
+pub struct Plugin {
+    /// Path to the executable.
+    path: PathBuf,
+    /// The global options given before the name of the plugin.
+    options: Cli,
+    /// Arguments passed to the executable.
+    args: Vec<OsString>,
+}
+
+impl Plugin {
+    /// Find the [`Plugin`] a command line calls for,
+    /// if any.
+    #[inline]
+    pub fn find(args: &[OsString]) -> Option<Self> {
+        let start = skip_global_options(args)?;
+        let name = args.get(start)?.to_str()?;
+        if name.is_empty()
+            || name.starts_with(['-', '@'])
+            || name.contains(['/', '\\'])
+            || name == "help"
+            || Cli::command().find_subcommand(name).is_some()
+            || Path::new(name).exists()
+        {
+            return None;
+        }
+
+        let executable = format!("{PREFIX}{name}{suffix}", suffix = env::consts::EXE_SUFFIX);
+        let path = env::split_paths(&env::var_os("PATH")?)
+            .map(|directory| directory.join(&executable))
+            .find(|path| is_executable(path))?;
+        Some(Self {
+            path,
+            options: Cli::try_parse_from(&args[..start]).ok()?,
+            args: args[start + 1..].to_vec(),
+        })
+    }
+
+    /// Get the global options given before the name of this [`Plugin`].
+    #[inline]
+    pub const fn options(&self) -> &Cli {
+        &self.options
+    }
+
+    /// Run this [`Plugin`],
+    /// returning its exit code.
+    #[inline]
+    pub async fn run(self) -> Result<u8, CliError> {
+        let mut command = Command::new(&self.path);
+        command
+            .args(&self.args)
+            .env("ANSWER_VERSION", env!("CARGO_PKG_VERSION"))
+            .env("ANSWER_MODEL", CHAT_MODEL)
+            .envs(self.options.redaction.env());
+        if let Ok(path) = env::current_exe() {
+            command.env("ANSWER_BIN", path);
+        }
+        if let Some(directory) = dirs::config_dir() {
+            command.env("ANSWER_CONFIG_DIR", directory.join("answer"));
+        }
+        if let Some(directory) = dirs::data_dir() {
+            command.env("ANSWER_DATA_DIR", directory.join("answer"));
+        }
+
+        let profile = self.options.profile.as_deref().unwrap_or(DEFAULT_PROFILE);
+        command.env("ANSWER_PROFILE", profile);
+        if let Some(max_tokens) = self.options.max_tokens {
+            command.env("ANSWER_MAX_TOKENS", max_tokens.to_string());
+        }
+        match Limits::of(profile) {
+            Ok(Some(limits)) => {
+                if let Some(daily) = limits.daily {
+                    command.env("ANSWER_BUDGET_DAILY", daily.to_string());
+                }
+                if let Some(monthly) = limits.monthly {
+                    command.env("ANSWER_BUDGET_MONTHLY", monthly.to_string());
+                }
+                if let Some(max_tokens) = limits.max_tokens {
+                    command.env("ANSWER_BUDGET_MAX_TOKENS", max_tokens.to_string());
+                }
+            }
+            Ok(None) => {}
+            Err(err) => log::warn!("not passing the budget: {err}"),
+        }
+
+        let source = self
+            .args
+            .iter()
+            .filter_map(|arg| arg.to_str())
+            .find(|arg| is_conversation(arg));
+        // The argument may not be meant as a conversation,
+        // so the plugin runs without it if it cannot be parsed.
+        match source.map(parse_conversation) {
+            Some(Ok(conversation)) => {
+                command
+                    .env("ANSWER_CONVERSATION", serde_json::to_string(&conversation)?)
+                    .env("ANSWER_MODEL", Bot::default().model(&conversation));
+                if let Some(path) = &conversation.path {
+                    command.env("ANSWER_CONVERSATION_PATH", path);
+                }
+            }
+            Some(Err(err)) => log::debug!("not passing the conversation: {err}"),
+            None => {}
+        }
+
+        log::debug!("running {path:?}", path = self.path);
+        let status = command.status().await?;
+        // Plugins killed by a signal have no exit code.
+        Ok(status
+            .code()
+            .and_then(|code| u8::try_from(code).ok())
+            .unwrap_or(1))
+    }
+}
+
+/// Find the index of the first argument after the global options,
+/// along with their values.
+///
+/// Returns `None` if an option is not global.
+#[inline]
+fn skip_global_options(args: &[OsString]) -> Option<usize> {
+    let command = Cli::command();
+    let mut index = 1;
+    while let Some(arg) = args.get(index).and_then(|arg| arg.to_str()) {
+        let (option, attached) = if let Some(long) = arg.strip_prefix("--") {
+            let (name, value) = long.split_once('=').unzip();
+            let name = name.unwrap_or(long);
+            let option = command
+                .get_arguments()
+                .find(|option| option.get_long() == Some(name))?;
+            (option, value.is_some())
+        } else if let Some(short) = arg.strip_prefix('-') {
+            let mut chars = short.chars();
+            let name = chars.next()?;
+            let option = command
+                .get_arguments()
+                .find(|option| option.get_short() == Some(name))?;
+            (option, !chars.as_str().is_empty())
+        } else {
+            break;
+        };
+        if !option.is_global_set() {
+            return None;
+        }
+        index += if option.get_action().takes_values() && !attached {
+            2
+        } else {
+            1
+        };
+    }
+    Some(index)
+}
+
+/// Determine whether an argument names a conversation.
+#[inline]
+fn is_conversation(arg: &str) -> bool {
+    arg.strip_prefix('@').map_or_else(
+        || {
+            let path = Path::new(arg);
+            path.is_file()
+                && path
+                    .extension()
+                    .is_some_and(|extension| extension == "yml" || extension == "yaml")
+        },
+        |name| !name.is_empty(),
+    )
+}
+
+/// Determine whether a file is executable.
+#[inline]
+fn is_executable(path: &Path) -> bool {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+
+        path.metadata()
+            .is_ok_and(|metadata| metadata.is_file() && metadata.permissions().mode() & 0o111 != 0)
+    }
+    #[cfg(not(unix))]
+    {
+        path.is_file()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn subcommands_and_files_are_not_plugins() {
+        let args = |name: &str| vec![OsString::from("answer"), OsString::from(name)];
+        for name in ["prompts", "help", "--help", "@birthdates", "Cargo.toml"] {
+            assert!(Plugin::find(&args(name)).is_none());
+        }
+        assert!(Plugin::find(&args("no-such-plugin-on-path")).is_none());
+
+        let args = [
+            "answer",
+            "--profile",
+            "work",
+            "-vv",
+            "--redact",
+            "foo",
+            "--profile",
+        ]
+        .map(OsString::from);
+        assert_eq!(skip_global_options(&args), Some(5));
+        let args = ["answer", "--max-tokens=10", "foo"].map(OsString::from);
+        assert_eq!(skip_global_options(&args), Some(2));
+        let args = ["answer", "--save", "foo"].map(OsString::from);
+        assert_eq!(skip_global_options(&args), None);
+        assert!(is_conversation("@act-as-a-linux-terminal"));
+        assert!(!is_conversation("notes.txt"));
+    }
+}
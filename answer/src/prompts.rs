@@ -0,0 +1,289 @@
+//! A library of named prompts.
+//!
+//! Prompts are [`Conversation`](crate::Conversation) files that can be
+//! referred to by name (e.g., `answer @act-as-a-linux-terminal`).
+//! Names are looked up in the following places, in order:
+//!
+//! 1. the project library, `.answer/prompts` in the current directory,
+//! 2. the user library, `answer/prompts` in the user configuration directory,
+//! 3. the bundled library, which ships with the application.
+//!
+//! The first prompt found wins,
+//! so project prompts shadow user prompts,
+//! which in turn shadow bundled ones.
+
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+use std::env;
+use std::fmt;
+use std::fs;
+use std::io::{self};
+use std::path::Path;
+use std::path::PathBuf;
+use std::process::Command;
+
+use clap::Subcommand;
+
+use crate::CliError;
+
+/// Prompts that ship with the application.
+const BUNDLED: &[(&str, &str)] = &[(
+    "act-as-a-linux-terminal",
+    include_str!("../../examples/act-as-a-linux-terminal.yml"),
+)];
+
+/// File extensions recognized as prompt files.
+const EXTENSIONS: &[&str] = &["yml", "yaml"];
+
+/// Where a [`Prompt`] comes from.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Origin {
+    /// The project library, relative to the current directory.
+    Project,
+    /// The user library, in the user configuration directory.
+    User,
+    /// The bundled library.
+    Bundled,
+}
+
+impl fmt::Display for Origin {
+    #[inline]
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Project => write!(f, "project"),
+            Self::User => write!(f, "user"),
+            Self::Bundled => write!(f, "bundled"),
+        }
+    }
+}
+
+/// A named prompt in the library.
+#[derive(Clone, Debug)]
+pub struct Prompt {
+    /// The name of the [`Prompt`], i.e., its file stem.
+    pub name: String,
+    /// Where the [`Prompt`] was found.
+    pub origin: Origin,
+    /// The file holding the [`Prompt`], if it is not bundled.
+    pub path: Option<PathBuf>,
+}
+
+impl Prompt {
+    /// Read the contents of this [`Prompt`].
+    #[inline]
+    pub fn contents(&self) -> io::Result<Cow<'static, str>> {
+        match &self.path {
+            Some(path) => fs::read_to_string(path).map(Cow::Owned),
+            None => Ok(Cow::Borrowed(bundled(&self.name).unwrap_or_default())),
+        }
+    }
+}
+
+/// Manage the library of named prompts.
+#[derive(Debug, Subcommand)]
+pub enum PromptsCommand {
+    /// List available prompts along with where they come from.
+    List,
+    /// Print the contents of a prompt.
+    Show {
+        /// Name of the prompt.
+        name: String,
+    },
+    /// Open a prompt in your editor.
+    ///
+    /// Bundled prompts are copied to the user library before editing,
+    /// and unknown names create a new prompt there.
+    Edit {
+        /// Name of the prompt.
+        name: String,
+    },
+}
+
+impl PromptsCommand {
+    /// Run this [`PromptsCommand`].
+    #[inline]
+    pub fn run(self) -> Result<(), CliError> {
+        match self {
+            Self::List => {
+                for prompt in list() {
+                    match prompt.path {
+                        Some(path) => println!(
+                            "{name}\t{origin}\t{path}",
+                            name = prompt.name,
+                            origin = prompt.origin,
+                            path = path.display()
+                        ),
+                        None => println!(
+                            "{name}\t{origin}",
+                            name = prompt.name,
+                            origin = prompt.origin
+                        ),
+                    }
+                }
+            }
+            Self::Show { name } => {
+                let prompt = find(&name).ok_or(CliError::PromptNotFound(name))?;
+                print!("{contents}", contents = prompt.contents()?);
+            }
+            Self::Edit { name } => {
+                let path = match find(&name) {
+                    Some(Prompt {
+                        path: Some(path), ..
+                    }) => path,
+                    prompt => {
+                        let directory = user_directory().ok_or(CliError::NoConfigDir)?;
+                        fs::create_dir_all(&directory)?;
+
+                        let path = directory.join(format!("{name}.yml"));
+                        let contents = match prompt {
+                            Some(prompt) => prompt.contents()?.into_owned(),
+                            None => "messages:\n  - role: system\n    content: \n".to_owned(),
+                        };
+                        fs::write(&path, contents)?;
+                        path
+                    }
+                };
+                edit(&path)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Find a [`Prompt`] by name, honoring the search order.
+#[inline]
+pub fn find(name: &str) -> Option<Prompt> {
+    for (origin, directory) in directories() {
+        for extension in EXTENSIONS {
+            let path = directory.join(format!("{name}.{extension}"));
+            if path.is_file() {
+                return Some(Prompt {
+                    name: name.to_owned(),
+                    origin,
+                    path: Some(path),
+                });
+            }
+        }
+    }
+
+    bundled(name).map(|_| Prompt {
+        name: name.to_owned(),
+        origin: Origin::Bundled,
+        path: None,
+    })
+}
+
+/// List every visible [`Prompt`], sorted by name.
+///
+/// Shadowed prompts are not listed.
+#[inline]
+pub fn list() -> Vec<Prompt> {
+    let mut prompts = BTreeMap::new();
+
+    for (origin, directory) in directories() {
+        let Ok(entries) = fs::read_dir(&directory) else {
+            continue;
+        };
+        for path in entries.filter_map(Result::ok).map(|entry| entry.path()) {
+            if let Some(name) = prompt_name(&path) {
+                prompts.entry(name.clone()).or_insert(Prompt {
+                    name,
+                    origin,
+                    path: Some(path),
+                });
+            }
+        }
+    }
+
+    for (name, _) in BUNDLED {
+        prompts.entry((*name).to_owned()).or_insert(Prompt {
+            name: (*name).to_owned(),
+            origin: Origin::Bundled,
+            path: None,
+        });
+    }
+
+    prompts.into_values().collect()
+}
+
+/// The prompt directories on disk, in search order.
+#[inline]
+fn directories() -> Vec<(Origin, PathBuf)> {
+    let mut directories = vec![(Origin::Project, Path::new(".answer").join("prompts"))];
+    if let Some(directory) = user_directory() {
+        directories.push((Origin::User, directory));
+    }
+    directories
+}
+
+/// The user prompt directory.
+#[inline]
+fn user_directory() -> Option<PathBuf> {
+    dirs::config_dir().map(|directory| directory.join("answer").join("prompts"))
+}
+
+/// Get the contents of a bundled prompt.
+#[inline]
+fn bundled(name: &str) -> Option<&'static str> {
+    BUNDLED
+        .iter()
+        .find_map(|&(bundled, contents)| (bundled == name).then_some(contents))
+}
+
+/// Get the name of a prompt from its file [`Path`].
+#[inline]
+pub fn prompt_name(path: &Path) -> Option<String> {
+    let extension = path.extension()?.to_str()?;
+    if !path.is_file() || !EXTENSIONS.contains(&extension) {
+        return None;
+    }
+    path.file_stem()?.to_str().map(ToOwned::to_owned)
+}
+
+/// Open a file in the user's editor and wait for it to close.
+#[inline]
+fn edit(path: &Path) -> Result<(), CliError> {
+    let editor = env::var("VISUAL")
+        .ok()
+        .filter(|editor| !editor.trim().is_empty())
+        .or_else(|| env::var("EDITOR").ok())
+        .filter(|editor| !editor.trim().is_empty())
+        .unwrap_or_else(|| "vi".to_owned());
+
+    // Like Git, the editor is run by the shell,
+    // so that it can have arguments, such as `code --wait`.
+    #[cfg(unix)]
+    let status = Command::new("sh")
+        .arg("-c")
+        .arg(format!("{editor} \"$@\""))
+        .arg(&editor)
+        .arg(path)
+        .status()?;
+    #[cfg(not(unix))]
+    let status = {
+        let mut words = editor.split_whitespace();
+        Command::new(words.next().unwrap_or("vi"))
+            .args(words)
+            .arg(path)
+            .status()?
+    };
+    if status.success() {
+        Ok(())
+    } else {
+        Err(CliError::Editor(editor))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Conversation;
+
+    #[test]
+    fn bundled_prompts_are_conversations() {
+        for (name, contents) in BUNDLED {
+            let conversation = Conversation::from_reader(contents.as_bytes());
+            assert!(conversation.is_ok(), "{name} is not a valid conversation");
+        }
+    }
+}
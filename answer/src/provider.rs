@@ -0,0 +1,156 @@
+//! Offline providers of replies.
+//!
+//! Replies normally come from the API,
+//! but `answer test` can also run against a mock provider (`--mock`),
+//! which repeats the last user message back,
+//! or a replay provider (`--replay FILE`),
+//! which reads replies recorded earlier in a JSON file.
+//! With `--record`,
+//! replies missing from the file are requested from the API and added to it.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::fs::File;
+use std::io::BufReader;
+use std::io::{self};
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::Mutex;
+
+use serde_json::json;
+use sha2::Digest;
+use sha2::Sha256;
+
+use crate::CliError;
+use crate::Conversation;
+use crate::Role;
+
+/// Where replies come from.
+#[derive(Clone, Debug, Default)]
+pub enum Provider {
+    /// The API.
+    #[default]
+    Live,
+    /// Replies repeat the last user message.
+    Mock,
+    /// Replies recorded in a file.
+    Replay(Arc<Replay>),
+}
+
+/// Replies recorded in a file,
+/// keyed by a hash of the request.
+#[derive(Debug)]
+pub struct Replay {
+    /// The file of recorded replies.
+    path: PathBuf,
+    /// Recorded replies by request hash.
+    replies: Mutex<BTreeMap<String, String>>,
+    /// Whether missing replies are requested and recorded.
+    pub records: bool,
+}
+
+impl Replay {
+    /// Open a file of recorded replies.
+    ///
+    /// A missing file has no replies if recording,
+    /// and is an error otherwise.
+    #[inline]
+    pub fn open(path: &Path, records: bool) -> Result<Self, CliError> {
+        let replies = match File::open(path) {
+            Ok(file) => serde_json::from_reader(BufReader::new(file))?,
+            Err(err) if records && err.kind() == io::ErrorKind::NotFound => BTreeMap::new(),
+            Err(err) => return Err(err.into()),
+        };
+        Ok(Self {
+            path: path.to_owned(),
+            replies: Mutex::new(replies),
+            records,
+        })
+    }
+
+    /// Get the recorded reply to a request.
+    #[inline]
+    pub fn get(&self, key: &str) -> Option<String> {
+        self.replies
+            .lock()
+            .expect("the replay lock should not be poisoned")
+            .get(key)
+            .cloned()
+    }
+
+    /// Record the reply to a request,
+    /// writing the file back.
+    #[inline]
+    pub fn record(&self, key: String, reply: String) -> io::Result<()> {
+        let mut replies = self
+            .replies
+            .lock()
+            .expect("the replay lock should not be poisoned");
+        replies.insert(key, reply);
+        fs::write(&self.path, serde_json::to_string_pretty(&*replies)?)
+    }
+}
+
+/// Compute the key of a request for a [`Conversation`].
+#[inline]
+pub fn key(
+    model: &str,
+    max_tokens: Option<u16>,
+    conversation: &Conversation,
+) -> io::Result<String> {
+    let request = json!({
+        "model": model,
+        "max_tokens": max_tokens,
+        "messages": conversation.to_api()?,
+    });
+    Ok(format!(
+        "{hash:x}",
+        hash = Sha256::digest(request.to_string())
+    ))
+}
+
+/// Get the mock reply to a [`Conversation`],
+/// which is its last user message.
+#[inline]
+pub fn mock_reply(conversation: &Conversation) -> String {
+    conversation
+        .messages
+        .iter()
+        .rev()
+        .find(|message| message.role == Role::User)
+        .map(|message| message.content.to_string())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Message;
+
+    #[test]
+    fn replies_are_replayed_by_request() {
+        let mut conversation = Conversation::default();
+        conversation.push(Message::from_system("You are terse."));
+        conversation.push(Message::from_user("Malcolm X"));
+        assert_eq!(mock_reply(&conversation), "Malcolm X");
+
+        let path = std::env::temp_dir().join(format!("answer-replay-{}.json", std::process::id()));
+        assert!(Replay::open(&path, false).is_err());
+
+        let replay = Replay::open(&path, true).unwrap();
+        let first = key("model", None, &conversation).unwrap();
+        assert_ne!(first, key("model", Some(10), &conversation).unwrap());
+        replay.record(first.clone(), "1925".to_owned()).unwrap();
+
+        let replay = Replay::open(&path, false).unwrap();
+        assert_eq!(replay.get(&first).as_deref(), Some("1925"));
+        conversation.push(Message::from_user("Malcolm Y"));
+        assert_eq!(
+            replay.get(&key("model", None, &conversation).unwrap()),
+            None
+        );
+
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,302 @@
+//! Serving answers over HTTP.
+//!
+//! The following endpoints are exposed:
+//!
+//! - `POST /answer` takes a conversation (YAML or JSON) as body,
+//!   which may neither list MCP servers nor refer to local images,
+//!   as these would run programs and read files on the server,
+//! - `POST /prompts/{name}` takes a user message as body,
+//!   and answers it in the context of a named prompt,
+//! - `GET /prompts` lists named prompts.
+//!
+//! Answers are streamed as server-sent events,
+//! each holding a piece of the answer,
+//! followed by a `done` event (or an `error` event, if anything failed).
+//! A line break in a piece of the answer splits it over several data fields.
+//!
+//! Answers are logged in the history and spent from the budget,
+//! and secrets are redacted with `--redact`,
+//! but answers are not moderated.
+
+use std::convert::Infallible;
+use std::io::{self};
+use std::net::IpAddr;
+use std::net::Ipv4Addr;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::task::Context;
+use std::task::Poll;
+use std::time::SystemTime;
+
+use axum::extract::Path;
+use axum::extract::State;
+use axum::http::StatusCode;
+use axum::response::sse::Event;
+use axum::response::sse::KeepAlive;
+use axum::response::sse::Sse;
+use axum::routing::get;
+use axum::routing::post;
+use axum::Json;
+use axum::Router;
+use clap::Args;
+use futures::channel::mpsc;
+use futures::Stream;
+use futures::StreamExt;
+use serde_json::json;
+use tokio::io::AsyncWrite;
+
+use crate::history::Invocation;
+use crate::parse_conversation;
+use crate::prompts;
+use crate::Bot;
+use crate::CliError;
+use crate::Conversation;
+use crate::Message;
+
+/// Serve answers over HTTP.
+#[derive(Debug, Args)]
+pub struct ServeCommand {
+    /// Address to listen on.
+    #[arg(long, default_value_t = IpAddr::V4(Ipv4Addr::LOCALHOST))]
+    host: IpAddr,
+
+    /// Port to listen on.
+    #[arg(short, long, default_value_t = 8080)]
+    port: u16,
+}
+
+impl ServeCommand {
+    /// Run this [`ServeCommand`].
+    #[inline]
+    pub async fn run(self, bot: Bot) -> Result<(), CliError> {
+        let app = Router::new()
+            .route("/answer", post(answer))
+            .route("/prompts", get(list_prompts))
+            .route("/prompts/:name", post(answer_prompt))
+            .with_state(bot);
+
+        let address = SocketAddr::new(self.host, self.port);
+        log::info!("listening on http://{address}");
+        axum::Server::try_bind(&address)
+            .map_err(io::Error::other)?
+            .serve(app.into_make_service())
+            .await
+            .map_err(io::Error::other)?;
+        Ok(())
+    }
+}
+
+/// A stream of server-sent [`Event`]s.
+type Events = Sse<Pin<Box<dyn Stream<Item = Result<Event, Infallible>> + Send>>>;
+
+/// Answer a conversation given as body.
+#[inline]
+async fn answer(State(bot): State<Bot>, body: String) -> Result<Events, (StatusCode, String)> {
+    Ok(stream(bot, posted_conversation(&body)?))
+}
+
+/// Answer a user message given as body,
+/// in the context of a named prompt.
+#[inline]
+async fn answer_prompt(
+    State(bot): State<Bot>,
+    Path(name): Path<String>,
+    body: String,
+) -> Result<Events, (StatusCode, String)> {
+    Ok(stream(bot, prompt_conversation(&name, body)?))
+}
+
+/// List the names of the prompts in the library.
+#[inline]
+async fn list_prompts() -> Json<Vec<String>> {
+    Json(
+        prompts::list()
+            .into_iter()
+            .map(|prompt| prompt.name)
+            .collect(),
+    )
+}
+
+/// Parse a [`Conversation`] posted as body.
+///
+/// Only conversations on disk may start MCP servers or read local images,
+/// and served conversations are neither chains nor compacted.
+#[inline]
+fn posted_conversation(body: &str) -> Result<Conversation, (StatusCode, String)> {
+    let conversation = Conversation::from_reader(body.as_bytes())
+        .map_err(|err| (StatusCode::BAD_REQUEST, err.to_string()))?;
+    if !conversation.mcp.is_empty() {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            "posted conversations cannot use MCP servers".to_owned(),
+        ));
+    }
+    if conversation.has_local_images() {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            "posted conversations cannot use local images".to_owned(),
+        ));
+    }
+    if !conversation.steps.is_empty() {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            "posted conversations cannot be chains".to_owned(),
+        ));
+    }
+    if conversation.compaction.is_some() {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            "posted conversations cannot be compacted".to_owned(),
+        ));
+    }
+    Ok(conversation)
+}
+
+/// Get the [`Conversation`] of a named prompt,
+/// followed by a user message.
+#[inline]
+fn prompt_conversation(name: &str, message: String) -> Result<Conversation, (StatusCode, String)> {
+    // Names come from the network,
+    // so they must not be able to reach outside the library.
+    if name.starts_with('.') || name.contains(['/', '\\']) {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            format!("invalid prompt name {name:?}"),
+        ));
+    }
+
+    let mut conversation = parse_conversation(&format!("@{name}")).map_err(|err| match err {
+        CliError::PromptNotFound(_) => (StatusCode::NOT_FOUND, err.to_string()),
+        err => (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()),
+    })?;
+    conversation.path = None;
+    if !message.trim().is_empty() {
+        conversation.push(Message::from_user(message));
+    }
+    Ok(conversation)
+}
+
+/// Stream the answer to a [`Conversation`] as server-sent [`Event`]s.
+#[inline]
+fn stream(bot: Bot, conversation: Conversation) -> Events {
+    let (sender, receiver) = mpsc::unbounded();
+    tokio::spawn(async move {
+        let started = SystemTime::now();
+        let result = bot.reply(&conversation, EventWriter(sender.clone())).await;
+        let event = match result {
+            Ok(reply) => {
+                bot.log(&Invocation {
+                    started,
+                    duration: started.elapsed().unwrap_or_default(),
+                    path: None,
+                    conversation: &conversation,
+                    parameters: json!({
+                        "model": bot.model(&conversation),
+                        "max_tokens": bot.max_tokens,
+                        "serve": true,
+                    }),
+                    reply: &reply.text,
+                    usage: reply.usage,
+                });
+                Event::default().event("done").data("")
+            }
+            Err(err) => {
+                log::warn!("could not answer: {err}");
+                data_event(&err.to_string()).event("error")
+            }
+        };
+        // The client may be gone already.
+        let _ = sender.unbounded_send(event);
+    });
+
+    Sse::new(receiver.map(Ok).boxed()).keep_alive(KeepAlive::default())
+}
+
+/// Create a server-sent [`Event`] holding a text.
+///
+/// Each line of the text goes in its own data field,
+/// as carriage returns cannot be sent over server-sent events
+/// (and [`Event::data`] panics on them).
+#[inline]
+fn data_event(text: &str) -> Event {
+    Event::default().data(text.replace("\r\n", "\n").replace('\r', "\n"))
+}
+
+/// An [`AsyncWrite`]r sending each write as a server-sent [`Event`].
+struct EventWriter(mpsc::UnboundedSender<Event>);
+
+impl AsyncWrite for EventWriter {
+    #[inline]
+    fn poll_write(
+        self: Pin<&mut Self>,
+        _: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let event = data_event(&String::from_utf8_lossy(buf));
+        Poll::Ready(
+            self.0
+                .unbounded_send(event)
+                .map(|()| buf.len())
+                .map_err(|_| io::ErrorKind::BrokenPipe.into()),
+        )
+    }
+
+    #[inline]
+    fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    #[inline]
+    fn poll_shutdown(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn prompts_are_looked_up_in_the_library() {
+        let conversation =
+            prompt_conversation("act-as-a-linux-terminal", "pwd".to_owned()).unwrap();
+        let last = conversation.messages.last().unwrap();
+        assert_eq!(last.content.to_string(), "pwd");
+
+        for name in ["../secrets", "a/b", ".hidden"] {
+            assert_eq!(
+                prompt_conversation(name, String::new()).unwrap_err().0,
+                StatusCode::BAD_REQUEST
+            );
+        }
+        assert_eq!(
+            prompt_conversation("no-such-prompt", String::new())
+                .unwrap_err()
+                .0,
+            StatusCode::NOT_FOUND
+        );
+
+        let event = format!("{:?}", data_event("one\r\ntwo\rthree\n"));
+        assert!(event.contains(r"data:one\ndata:two\ndata:three\ndata:\n"));
+    }
+
+    #[test]
+    fn posted_conversations_cannot_reach_the_server() {
+        assert!(posted_conversation("messages: [{content: Hi!}]").is_ok());
+        for body in [
+            "mcp: [{name: shell, command: sh}]",
+            "messages: [{content: [{image: cat.png}]}]",
+            "steps: [{id: a, messages: [{content: Hi!}]}]",
+            "compaction: {threshold: 1000}",
+        ] {
+            assert_eq!(
+                posted_conversation(body).unwrap_err().0,
+                StatusCode::BAD_REQUEST
+            );
+        }
+        assert!(posted_conversation(
+            "messages: [{content: [{image: https://example.com/cat.png}]}]"
+        )
+        .is_ok());
+    }
+}
@@ -0,0 +1,192 @@
+//! Suggesting shell commands.
+//!
+//! The model is asked for a single command for the current operating system
+//! and shell,
+//! which the user can then run, edit or cancel.
+//! When asked to,
+//! the output of the command is sent back for an explanation.
+
+use std::env;
+use std::path::Path;
+use std::process::Command;
+use std::process::Stdio;
+use std::time::SystemTime;
+
+use rustyline::error::ReadlineError;
+use rustyline::DefaultEditor;
+use serde_json::json;
+use tokio::io::AsyncWrite;
+
+use crate::history::Invocation;
+use crate::Bot;
+use crate::CliError;
+use crate::Conversation;
+use crate::Message;
+use crate::Reply;
+
+/// What the user chose to do with a suggested command.
+enum Choice {
+    /// Run the command.
+    Run,
+    /// Run the command and explain its output.
+    Explain,
+    /// Edit the command before choosing again.
+    Edit,
+    /// Do nothing.
+    Cancel,
+}
+
+/// Suggest a shell command for a request and let the user act on it.
+#[inline]
+pub async fn suggest(bot: &Bot, request: &str) -> Result<(), CliError> {
+    let shell = shell();
+    let name = Path::new(&shell)
+        .file_name()
+        .map_or_else(|| shell.clone(), |name| name.to_string_lossy().into_owned());
+
+    let mut conversation = Conversation::default();
+    conversation.push(Message::from_system(format!(
+        "You translate requests into shell commands. \
+         Reply with a single {name} command for {os} that does what is asked, \
+         with no explanation, no comments and no code fences. \
+         Prefer portable, non-destructive commands.",
+        os = env::consts::OS
+    )));
+    conversation.push(Message::from_user(request));
+
+    let suggestion = ask_bot(bot, &conversation, Vec::new(), request).await?;
+    let mut command = strip_fences(&suggestion);
+    let mut editor = DefaultEditor::new()?;
+    let choice = loop {
+        println!("$ {command}");
+        match ask(&mut editor)? {
+            Choice::Edit => match editor.readline_with_initial("$ ", (&command, "")) {
+                Ok(edited) => command = edited.trim().to_owned(),
+                Err(ReadlineError::Interrupted | ReadlineError::Eof) => break Choice::Cancel,
+                Err(err) => return Err(err.into()),
+            },
+            choice => break choice,
+        }
+    };
+
+    let status = match choice {
+        Choice::Run => Command::new(&shell).arg("-c").arg(&command).status()?,
+        Choice::Explain => {
+            let output = Command::new(&shell)
+                .arg("-c")
+                .arg(&command)
+                .stdin(Stdio::inherit())
+                .output()?;
+            let stdout = String::from_utf8_lossy(&output.stdout);
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            print!("{stdout}");
+            eprint!("{stderr}");
+
+            conversation.push(Message::from_assistant(command.as_str()));
+            conversation.push(Message::from_user(format!(
+                "I ran `{command}`, which exited with {status}.\n\n\
+                 Standard output:\n```\n{stdout}\n```\n\n\
+                 Standard error:\n```\n{stderr}\n```\n\n\
+                 Briefly explain what happened.",
+                status = output.status
+            )));
+            println!();
+            ask_bot(bot, &conversation, tokio::io::stdout(), request).await?;
+            println!();
+            output.status
+        }
+        Choice::Edit | Choice::Cancel => return Ok(()),
+    };
+
+    if status.success() {
+        Ok(())
+    } else {
+        Err(CliError::Command(status))
+    }
+}
+
+/// Get a reply of a [`Bot`] to a [`Writer`](AsyncWrite),
+/// logging it in the history.
+#[inline]
+async fn ask_bot<W>(
+    bot: &Bot,
+    conversation: &Conversation,
+    writer: W,
+    request: &str,
+) -> Result<String, CliError>
+where
+    W: AsyncWrite + Send + Unpin,
+{
+    let started = SystemTime::now();
+    let Reply { text: reply, usage } = bot.reply(conversation, writer).await?;
+    bot.log(&Invocation {
+        started,
+        duration: started.elapsed().unwrap_or_default(),
+        path: None,
+        conversation,
+        parameters: json!({
+            "model": bot.model(conversation),
+            "max_tokens": bot.max_tokens,
+            "shell": request,
+        }),
+        reply: &reply,
+        usage,
+    });
+    Ok(reply)
+}
+
+/// Ask the user what to do with a command.
+#[inline]
+fn ask(editor: &mut DefaultEditor) -> Result<Choice, CliError> {
+    loop {
+        let answer = match editor.readline("[r]un, e[x]plain, [e]dit or [c]ancel? ") {
+            Ok(answer) => answer,
+            Err(ReadlineError::Interrupted | ReadlineError::Eof) => return Ok(Choice::Cancel),
+            Err(err) => return Err(err.into()),
+        };
+        match answer.trim().to_lowercase().as_str() {
+            "r" | "run" => return Ok(Choice::Run),
+            "x" | "explain" => return Ok(Choice::Explain),
+            "e" | "edit" => return Ok(Choice::Edit),
+            "c" | "cancel" | "" => return Ok(Choice::Cancel),
+            _ => {}
+        }
+    }
+}
+
+/// The shell of the user,
+/// falling back to the POSIX shell.
+#[inline]
+fn shell() -> String {
+    env::var("SHELL")
+        .ok()
+        .filter(|shell| !shell.is_empty())
+        .unwrap_or_else(|| "sh".to_owned())
+}
+
+/// Remove code fences around a command,
+/// in case the model did not follow instructions.
+#[inline]
+fn strip_fences(reply: &str) -> String {
+    let reply = reply.trim();
+    let reply = reply
+        .strip_prefix("```")
+        .and_then(|reply| reply.strip_suffix("```"))
+        .map_or(reply, |inner| {
+            // Drop the language of the fence, if any.
+            inner.split_once('\n').map_or(inner, |(_, body)| body)
+        });
+    reply.trim().to_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fences_are_stripped() {
+        assert_eq!(strip_fences("ls -l\n"), "ls -l");
+        assert_eq!(strip_fences("```bash\nls -l\n```"), "ls -l");
+        assert_eq!(strip_fences("```\nls -l\n```"), "ls -l");
+    }
+}
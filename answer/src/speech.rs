@@ -0,0 +1,206 @@
+//! Text-to-speech.
+//!
+//! Replies are sent to the speech endpoint once complete,
+//! and the audio is written to a file or the standard output.
+
+use std::fs;
+use std::io::Write;
+use std::io::{self};
+use std::path::Path;
+use std::path::PathBuf;
+
+use async_openai::error::OpenAIError;
+use async_openai::Client;
+use clap::Args;
+use clap::ValueEnum;
+use serde::Serialize;
+use serde_json::json;
+use tokio::io::AsyncReadExt;
+
+use crate::budget;
+use crate::Bot;
+use crate::BotError;
+use crate::CliError;
+
+/// Model used to synthesize speech.
+const SPEECH_MODEL: &str = "tts-1";
+
+/// Path meaning the standard output.
+pub const STDOUT: &str = "-";
+
+/// An audio format for synthesized speech.
+#[derive(Clone, Copy, Debug, Default, Serialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum AudioFormat {
+    /// MP3, for general use.
+    #[default]
+    Mp3,
+    /// Opus, for streaming and low latency.
+    Opus,
+    /// AAC, for digital audio compression.
+    Aac,
+    /// FLAC, for lossless compression.
+    Flac,
+    /// Uncompressed WAV.
+    Wav,
+    /// Raw 24kHz 16-bit PCM samples.
+    Pcm,
+}
+
+/// Options for synthesized speech.
+#[derive(Debug, Args)]
+pub struct SpeechOptions {
+    /// Voice used to speak.
+    #[arg(long, default_value = "alloy")]
+    voice: String,
+
+    /// Format of the audio.
+    #[arg(long, value_enum, default_value_t)]
+    audio_format: AudioFormat,
+}
+
+impl Bot {
+    /// Synthesize speech for a text.
+    #[inline]
+    pub async fn speak(&self, text: &str, options: &SpeechOptions) -> Result<Vec<u8>, BotError> {
+        let text = self.redact(text);
+        let prompt_tokens = budget::count_tokens(&text);
+        let (_, reservation) = self.check_budget(SPEECH_MODEL, prompt_tokens, Some(0))?;
+        let audio = synthesize(&self.client()?, &text, options).await?;
+        reservation.settle("", None);
+        Ok(audio)
+    }
+}
+
+/// Synthesize speech for a text with a [`Client`].
+///
+/// The request is made by hand,
+/// as [`Client`] does not support the speech endpoint.
+#[inline]
+async fn synthesize(
+    client: &Client,
+    text: &str,
+    options: &SpeechOptions,
+) -> Result<Vec<u8>, BotError> {
+    let response = reqwest::Client::new()
+        .post(format!("{base}/audio/speech", base = client.api_base()))
+        .bearer_auth(client.api_key())
+        .json(&json!({
+            "model": SPEECH_MODEL,
+            "input": text,
+            "voice": options.voice,
+            "response_format": options.audio_format,
+        }))
+        .send()
+        .await
+        .and_then(reqwest::Response::error_for_status)
+        .map_err(OpenAIError::from)?;
+    Ok(response.bytes().await.map_err(OpenAIError::from)?.into())
+}
+
+/// Write audio to a file [`Path`],
+/// or to the standard output if the path is [`STDOUT`].
+#[inline]
+pub fn write_audio(path: &Path, audio: &[u8]) -> io::Result<()> {
+    if path == Path::new(STDOUT) {
+        let mut stdout = io::stdout().lock();
+        stdout.write_all(audio)?;
+        stdout.flush()
+    } else {
+        fs::write(path, audio)
+    }
+}
+
+/// Speak a text.
+#[derive(Debug, Args)]
+pub struct SpeakCommand {
+    /// Text to speak.
+    ///
+    /// If not given,
+    /// the standard input is spoken instead.
+    text: Option<String>,
+
+    /// Path to write the audio to,
+    /// or `-` for the standard output.
+    #[arg(short, long, default_value = STDOUT)]
+    output: PathBuf,
+
+    /// Speech options.
+    #[command(flatten)]
+    options: SpeechOptions,
+}
+
+impl SpeakCommand {
+    /// Run this [`SpeakCommand`].
+    #[inline]
+    pub async fn run(self, bot: &Bot) -> Result<(), CliError> {
+        let text = match self.text {
+            Some(text) => text,
+            None => {
+                let mut text = String::new();
+                tokio::io::stdin().read_to_string(&mut text).await?;
+                text
+            }
+        };
+
+        let audio = bot.speak(&text, &self.options).await?;
+        write_audio(&self.output, &audio)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+    use std::net::TcpListener;
+    use std::thread;
+
+    use super::*;
+
+    #[test]
+    fn speech_is_read_from_stand_in_server() {
+        const AUDIO: &[u8] = b"ID3 canned audio";
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = Vec::new();
+            let mut buffer = [0; 1024];
+            // Read until the whole JSON body has arrived.
+            while !request.ends_with(b"}") {
+                let read = stream.read(&mut buffer).unwrap();
+                if read == 0 {
+                    break;
+                }
+                request.extend_from_slice(&buffer[..read]);
+            }
+            write!(
+                stream,
+                "HTTP/1.1 200 OK\r\ncontent-type: audio/mpeg\r\ncontent-length: {length}\r\n\r\n",
+                length = AUDIO.len()
+            )
+            .unwrap();
+            stream.write_all(AUDIO).unwrap();
+            String::from_utf8(request).unwrap()
+        });
+
+        let client = Client::default()
+            .with_api_key("test")
+            .with_api_base(format!("http://{address}/v1"));
+        let options = SpeechOptions {
+            voice: "nova".to_owned(),
+            audio_format: AudioFormat::Opus,
+        };
+        let audio = tokio::runtime::Runtime::new()
+            .unwrap()
+            .block_on(synthesize(&client, "Hello!", &options))
+            .unwrap();
+        assert_eq!(audio, AUDIO);
+
+        let request = server.join().unwrap();
+        assert!(request.starts_with("POST /v1/audio/speech "));
+        assert!(request.contains(r#""voice":"nova""#));
+        assert!(request.contains(r#""response_format":"opus""#));
+    }
+}
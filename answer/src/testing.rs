@@ -0,0 +1,502 @@
+//! Regression tests for prompts.
+//!
+//! Each test file names a conversation,
+//! an input to ask in its context,
+//! and assertions on the answer:
+//!
+//! ```yaml
+//! # tests/pwd.yml
+//! conversation: ../act-as-a-linux-terminal.yml # relative to this file, or @name
+//! input: pwd
+//! assert:
+//!   - regex: /home/\w+
+//!   - max_length: 200
+//!   - rubric: Only shows terminal output, without explanations.
+//! ```
+//!
+//! Answers that are JSON can also be checked with
+//! `json: { path: $.born.year, equals: 1925 }`.
+//! Rubrics are graded by the model itself.
+//!
+//! Tests can run without the API,
+//! against the mock provider (`--mock`)
+//! or replies recorded earlier (`--replay FILE`, recording with `--record`).
+//! The mock provider cannot grade,
+//! so rubrics are then reported as skipped.
+
+use std::fmt::Write as _;
+use std::fs;
+use std::fs::File;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use clap::Args;
+use console::style;
+use regex::Regex;
+use serde::Deserialize;
+use serde_json::json;
+use serde_json::Value;
+
+use crate::history::Invocation;
+use crate::parse_conversation;
+use crate::provider::Provider;
+use crate::provider::Replay;
+use crate::Bot;
+use crate::CliError;
+use crate::Conversation;
+use crate::Message;
+use crate::Reply;
+
+/// System prompt for grading answers against a rubric.
+const GRADER: &str = "You are grading the answer of an assistant against a rubric. \
+Reply with PASS if the answer satisfies the rubric, or FAIL otherwise, \
+followed by a one-sentence justification.";
+
+/// A test file.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Test {
+    /// Name of the test,
+    /// defaulting to the file name.
+    #[serde(default)]
+    name: Option<String>,
+    /// Path to a conversation YAML file relative to the test file,
+    /// or `@name` of a prompt in the library.
+    conversation: String,
+    /// The user message.
+    input: String,
+    /// [`Assertion`]s on the answer.
+    #[serde(rename = "assert")]
+    assertions: Vec<Assertion>,
+}
+
+/// An assertion on an answer.
+#[derive(Debug, Deserialize)]
+#[serde(untagged, deny_unknown_fields)]
+enum Assertion {
+    /// The answer contains a text.
+    Contains { contains: String },
+    /// The answer matches a regular expression.
+    Regex { regex: String },
+    /// The answer is JSON with a value at a path.
+    Json { json: JsonAssertion },
+    /// The answer has at most a number of characters.
+    MaxLength { max_length: usize },
+    /// The answer satisfies a rubric, as graded by the model.
+    Rubric { rubric: String },
+}
+
+/// An assertion on a value of a JSON answer.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct JsonAssertion {
+    /// Path to the value, such as `$.items[0].name`.
+    path: String,
+    /// The expected value.
+    equals: Value,
+}
+
+/// The outcome of a test.
+#[derive(Debug)]
+struct Outcome {
+    /// Name of the test.
+    name: String,
+    /// Failed assertions.
+    failures: Vec<String>,
+    /// Assertions that could not be checked.
+    skipped: Vec<String>,
+    /// Error that prevented the test from running, if any.
+    error: Option<String>,
+}
+
+impl Outcome {
+    /// Determine whether the test passed.
+    #[inline]
+    fn passed(&self) -> bool {
+        self.failures.is_empty() && self.error.is_none()
+    }
+}
+
+/// Run prompt regression tests.
+#[derive(Debug, Args)]
+pub struct TestCommand {
+    /// Paths to test YAML files.
+    #[arg(required = true)]
+    files: Vec<PathBuf>,
+
+    /// Path to write a JUnit XML report to.
+    #[arg(long, value_name = "FILE")]
+    junit: Option<PathBuf>,
+
+    /// Answer with the last user message instead of calling the API.
+    #[arg(long, conflicts_with = "replay")]
+    mock: bool,
+
+    /// Path to a JSON file of recorded replies to answer with instead of
+    /// calling the API.
+    #[arg(long, value_name = "FILE")]
+    replay: Option<PathBuf>,
+
+    /// Request replies missing from the replay file and record them.
+    #[arg(long, requires = "replay")]
+    record: bool,
+}
+
+impl TestCommand {
+    /// Run this [`TestCommand`].
+    #[inline]
+    pub async fn run(self, mut bot: Bot) -> Result<(), CliError> {
+        if let Some(path) = &self.replay {
+            bot.provider = Provider::Replay(Arc::new(Replay::open(path, self.record)?));
+        } else if self.mock {
+            bot.provider = Provider::Mock;
+        }
+        let mut outcomes = Vec::new();
+        for path in &self.files {
+            let outcome = run_test(&bot, path).await;
+            if outcome.passed() {
+                println!("{} {name}", style("PASS").green(), name = outcome.name);
+                for skipped in &outcome.skipped {
+                    println!("  {skipped}");
+                }
+            } else {
+                println!("{} {name}", style("FAIL").red(), name = outcome.name);
+                for failure in outcome.error.iter().chain(&outcome.failures) {
+                    println!("  {failure}");
+                }
+            }
+            outcomes.push(outcome);
+        }
+
+        let failed = outcomes.iter().filter(|outcome| !outcome.passed()).count();
+        let skipped: usize = outcomes.iter().map(|outcome| outcome.skipped.len()).sum();
+        print!(
+            "\n{passed} passed, {failed} failed",
+            passed = outcomes.len() - failed
+        );
+        if skipped > 0 {
+            print!(", {skipped} assertions skipped");
+        }
+        println!();
+
+        if let Some(path) = &self.junit {
+            fs::write(path, junit(&outcomes))?;
+        }
+        if failed > 0 {
+            return Err(CliError::TestsFailed {
+                failed,
+                total: outcomes.len(),
+            });
+        }
+        Ok(())
+    }
+}
+
+/// Run a test file,
+/// turning errors into a failed [`Outcome`].
+#[inline]
+async fn run_test(bot: &Bot, path: &Path) -> Outcome {
+    let mut outcome = Outcome {
+        name: path.file_stem().map_or_else(
+            || path.display().to_string(),
+            |stem| stem.to_string_lossy().into_owned(),
+        ),
+        failures: Vec::new(),
+        skipped: Vec::new(),
+        error: None,
+    };
+
+    let result: Result<_, CliError> = async {
+        let test: Test = serde_yaml::from_reader(File::open(path)?)?;
+        if let Some(name) = &test.name {
+            outcome.name.clone_from(name);
+        }
+
+        let mut conversation = load_conversation(path, &test.conversation)?;
+        conversation.push(Message::from_user(test.input.as_str()));
+        let started = SystemTime::now();
+        let Reply { text: reply, usage } = bot.reply(&conversation, Vec::new()).await?;
+        bot.log(&Invocation {
+            started,
+            duration: started.elapsed().unwrap_or_default(),
+            path: Some(path.to_owned()),
+            conversation: &conversation,
+            parameters: json!({
+                "model": bot.model(&conversation),
+                "max_tokens": bot.max_tokens,
+                "test": outcome.name,
+            }),
+            reply: &reply,
+            usage,
+        });
+
+        let mut failures = Vec::new();
+        let mut skipped = Vec::new();
+        for assertion in &test.assertions {
+            if let Some(reason) = skip(bot, assertion) {
+                skipped.push(reason);
+            } else if let Err(failure) = check(bot, &test.input, &reply, assertion).await? {
+                failures.push(failure);
+            }
+        }
+        Ok((failures, skipped))
+    }
+    .await;
+
+    match result {
+        Ok((failures, skipped)) => {
+            outcome.failures = failures;
+            outcome.skipped = skipped;
+        }
+        Err(err) => outcome.error = Some(err.to_string()),
+    }
+    outcome
+}
+
+/// Load the [`Conversation`] of a test,
+/// resolving paths relative to the test file.
+#[inline]
+fn load_conversation(test: &Path, conversation: &str) -> Result<Conversation, CliError> {
+    if conversation.starts_with('@') {
+        return parse_conversation(conversation);
+    }
+
+    let path = test.parent().map_or_else(
+        || PathBuf::from(conversation),
+        |parent| parent.join(conversation),
+    );
+    parse_conversation(&path.to_string_lossy())
+}
+
+/// Determine whether an [`Assertion`] cannot be checked by a [`Bot`].
+///
+/// Returns why it is skipped, if so.
+#[inline]
+fn skip(bot: &Bot, assertion: &Assertion) -> Option<String> {
+    match (&bot.provider, assertion) {
+        // The mock provider would only echo the grading prompt back.
+        (Provider::Mock, Assertion::Rubric { rubric }) => Some(format!(
+            "rubric {rubric:?} skipped, as the mock provider cannot grade"
+        )),
+        _ => None,
+    }
+}
+
+/// Check an [`Assertion`] on an answer.
+///
+/// Returns a description of the failure, if any.
+#[inline]
+async fn check(
+    bot: &Bot,
+    input: &str,
+    reply: &str,
+    assertion: &Assertion,
+) -> Result<Result<(), String>, CliError> {
+    let outcome = match assertion {
+        Assertion::Contains { contains } => ensure(reply.contains(contains.as_str()), || {
+            format!("answer does not contain {contains:?}")
+        }),
+        Assertion::Regex { regex } => {
+            let compiled = Regex::new(regex)?;
+            ensure(compiled.is_match(reply), || {
+                format!("answer does not match {regex:?}")
+            })
+        }
+        Assertion::Json { json } => check_json(reply, json),
+        Assertion::MaxLength { max_length } => {
+            let length = reply.chars().count();
+            ensure(length <= *max_length, || {
+                format!("answer has {length} characters, more than {max_length}")
+            })
+        }
+        Assertion::Rubric { rubric } => {
+            let mut conversation = Conversation::default();
+            conversation.push(Message::from_system(GRADER));
+            conversation.push(Message::from_user(format!(
+                "Question:\n{input}\n\nAnswer:\n{reply}\n\nRubric:\n{rubric}"
+            )));
+            let grade = bot.reply_to_writer(&conversation, Vec::new()).await?;
+            ensure(grade.trim_start().starts_with("PASS"), || {
+                format!(
+                    "rubric {rubric:?} not satisfied: {grade}",
+                    grade = grade.trim()
+                )
+            })
+        }
+    };
+    Ok(outcome)
+}
+
+/// Check a [`JsonAssertion`] on an answer.
+#[inline]
+fn check_json(reply: &str, assertion: &JsonAssertion) -> Result<(), String> {
+    let value: Value = serde_json::from_str(reply.trim())
+        .map_err(|err| format!("answer is not valid JSON: {err}"))?;
+    let pointer = json_pointer(&assertion.path);
+    match value.pointer(&pointer) {
+        Some(found) if found == &assertion.equals => Ok(()),
+        Some(found) => Err(format!(
+            "{path} is {found}, not {expected}",
+            path = assertion.path,
+            expected = assertion.equals
+        )),
+        None => Err(format!("{path} was not found", path = assertion.path)),
+    }
+}
+
+/// Convert a simple JSON path (such as `$.items[0].name`)
+/// to a JSON pointer (such as `/items/0/name`).
+#[inline]
+fn json_pointer(path: &str) -> String {
+    let path = path.strip_prefix('$').unwrap_or(path);
+    let mut pointer = String::new();
+    for segment in path
+        .split(['.', '[', ']'])
+        .filter(|segment| !segment.is_empty())
+    {
+        let segment = segment.replace('~', "~0").replace('/', "~1");
+        pointer.push('/');
+        pointer.push_str(&segment);
+    }
+    pointer
+}
+
+/// Fail with a description unless a condition holds.
+#[inline]
+fn ensure<F>(condition: bool, failure: F) -> Result<(), String>
+where
+    F: FnOnce() -> String,
+{
+    if condition {
+        Ok(())
+    } else {
+        Err(failure())
+    }
+}
+
+/// Format [`Outcome`]s as a JUnit XML report.
+#[inline]
+fn junit(outcomes: &[Outcome]) -> String {
+    let failures = outcomes
+        .iter()
+        .filter(|outcome| outcome.error.is_none() && !outcome.failures.is_empty())
+        .count();
+    let errors = outcomes
+        .iter()
+        .filter(|outcome| outcome.error.is_some())
+        .count();
+
+    let mut xml = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+    writeln!(
+        xml,
+        "<testsuite name=\"answer\" tests=\"{tests}\" failures=\"{failures}\" errors=\"{errors}\">",
+        tests = outcomes.len()
+    )
+    .expect("writing to a string should not fail");
+    for outcome in outcomes {
+        let name = escape(&outcome.name);
+        match (&outcome.error, outcome.failures.is_empty()) {
+            (Some(error), _) => {
+                writeln!(
+                    xml,
+                    "  <testcase name=\"{name}\">\n    <error message=\"{message}\"/>\n  </testcase>",
+                    message = escape(error)
+                )
+                .expect("writing to a string should not fail");
+            }
+            (None, false) => {
+                writeln!(
+                    xml,
+                    "  <testcase name=\"{name}\">\n    <failure message=\"{message}\">{details}</failure>\n  </testcase>",
+                    message = escape(&outcome.failures[0]),
+                    details = escape(&outcome.failures.join("\n"))
+                )
+                .expect("writing to a string should not fail");
+            }
+            (None, true) => {
+                writeln!(xml, "  <testcase name=\"{name}\"/>")
+                    .expect("writing to a string should not fail");
+            }
+        }
+    }
+    xml.push_str("</testsuite>\n");
+    xml
+}
+
+/// Escape a text for XML.
+#[inline]
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&apos;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn assertions_are_parsed_and_checked() {
+        let test: Test = serde_yaml::from_str(
+            "conversation: chat.yml\n\
+             input: Malcolm X\n\
+             assert:\n\
+             - contains: '1925'\n\
+             - max_length: 10\n\
+             - json: { path: '$.born[0]', equals: 1925 }\n",
+        )
+        .unwrap();
+        assert!(matches!(test.assertions[0], Assertion::Contains { .. }));
+        assert!(matches!(
+            test.assertions[1],
+            Assertion::MaxLength { max_length: 10 }
+        ));
+
+        let Assertion::Json { json } = &test.assertions[2] else {
+            panic!("expected a JSON assertion");
+        };
+        assert_eq!(json_pointer(&json.path), "/born/0");
+        assert_eq!(check_json(r#"{"born": [1925]}"#, json), Ok(()));
+        assert!(check_json(r#"{"born": [1926]}"#, json).is_err());
+        assert!(check_json("not JSON", json).is_err());
+    }
+
+    #[test]
+    fn rubrics_are_skipped_under_mock() {
+        let rubric = Assertion::Rubric {
+            rubric: "Polite.".to_owned(),
+        };
+        let bot = Bot {
+            provider: Provider::Mock,
+            ..Bot::default()
+        };
+        assert!(skip(&bot, &rubric).is_some());
+        assert!(skip(&Bot::default(), &rubric).is_none());
+        assert!(skip(&bot, &Assertion::MaxLength { max_length: 10 }).is_none());
+    }
+
+    #[test]
+    fn junit_reports_are_escaped() {
+        let outcomes = [
+            Outcome {
+                name: "passing".to_owned(),
+                failures: Vec::new(),
+                skipped: Vec::new(),
+                error: None,
+            },
+            Outcome {
+                name: "failing".to_owned(),
+                failures: vec!["answer does not contain \"<b>\"".to_owned()],
+                skipped: Vec::new(),
+                error: None,
+            },
+        ];
+        let xml = junit(&outcomes);
+        assert!(xml.contains("tests=\"2\" failures=\"1\" errors=\"0\""));
+        assert!(xml.contains("&quot;&lt;b&gt;&quot;"));
+    }
+}
@@ -0,0 +1,218 @@
+//! Branching [`Conversation`]s.
+//!
+//! A [`Message`] follows the previous one in the file,
+//! unless it names another `parent`,
+//! so a single file can hold a tree of alternative branches:
+//!
+//! ```yaml
+//! messages:
+//!   - role: system
+//!     content: You are a date of birth checker.
+//!   - id: question
+//!     content: Malcolm X
+//!   - role: assistant
+//!     content: Malcolm X was born on May 19th, 1925.
+//!   - role: assistant
+//!     parent: question
+//!     content: May 19, 1925.
+//! ```
+//!
+//! Messages without an explicit `id` are identified by their position in the
+//! file, starting at one.
+
+use std::io::Write;
+use std::io::{self};
+
+use clap::Args;
+
+use crate::parse_conversation;
+use crate::snippet;
+use crate::CliError;
+use crate::Conversation;
+use crate::Message;
+
+impl Conversation {
+    /// Get the identifier of the [`Message`] at a position.
+    #[inline]
+    pub fn id(&self, index: usize) -> String {
+        self.messages[index]
+            .id
+            .clone()
+            .unwrap_or_else(|| (index + 1).to_string())
+    }
+
+    /// Find the position of the [`Message`] with the given identifier.
+    ///
+    /// Explicit identifiers take precedence over positions.
+    #[inline]
+    pub fn find(&self, id: &str) -> Option<usize> {
+        self.messages
+            .iter()
+            .position(|message| message.id.as_deref() == Some(id))
+            .or_else(|| {
+                id.parse::<usize>()
+                    .ok()
+                    .filter(|&position| 0 < position && position <= self.messages.len())
+                    .map(|position| position - 1)
+            })
+    }
+
+    /// Get the position of the parent of the [`Message`] at a position,
+    /// if any.
+    #[inline]
+    pub fn parent(&self, index: usize) -> Option<usize> {
+        match &self.messages[index].parent {
+            Some(parent) => {
+                let found = self.find(parent);
+                if found.is_none() {
+                    log::warn!(
+                        "unknown parent {parent:?} of message {id}",
+                        id = self.id(index)
+                    );
+                }
+                found
+            }
+            None => index.checked_sub(1),
+        }
+    }
+
+    /// Determine whether this [`Conversation`] has more than one branch.
+    #[inline]
+    pub fn is_branched(&self) -> bool {
+        (0..self.messages.len()).any(|index| self.parent(index) != index.checked_sub(1))
+    }
+
+    /// Get the [`Conversation`] leading to the [`Message`] at a position,
+    /// from the root of its branch.
+    #[inline]
+    pub fn thread(&self, index: Option<usize>) -> Self {
+        let mut path = Vec::new();
+        let mut current = index;
+        while let Some(index) = current {
+            // Malformed files may contain cycles.
+            if path.contains(&index) {
+                log::warn!("cycle found at message {id}", id = self.id(index));
+                break;
+            }
+            path.push(index);
+            current = self.parent(index);
+        }
+
+        Self {
+            messages: path
+                .into_iter()
+                .rev()
+                .map(|index| self.messages[index].clone())
+                .collect(),
+            mcp: self.mcp.clone(),
+            ..Self::default()
+        }
+    }
+
+    /// Append a [`Message`] as a child of the [`Message`] at a position.
+    ///
+    /// Returns the position of the new [`Message`].
+    #[inline]
+    pub fn append(&mut self, parent: Option<usize>, mut message: Message) -> usize {
+        let last = self.messages.len().checked_sub(1);
+        if let (Some(parent), false) = (parent, parent == last) {
+            // Pin the identifier of the parent,
+            // so that it survives edits to the file.
+            let id = self.id(parent);
+            self.messages[parent].id = Some(id.clone());
+            message.parent = Some(id);
+        }
+
+        self.messages.push(message);
+        self.messages.len() - 1
+    }
+}
+
+/// Print the branch structure of a conversation.
+#[derive(Debug, Args)]
+pub struct TreeCommand {
+    /// Path to a conversation YAML file,
+    /// or `@name` of a prompt in the library.
+    conversation: String,
+}
+
+impl TreeCommand {
+    /// Run this [`TreeCommand`].
+    #[inline]
+    pub fn run(self) -> Result<(), CliError> {
+        let conversation = parse_conversation(&self.conversation)?;
+
+        let mut children = vec![Vec::new(); conversation.messages.len()];
+        let mut roots = Vec::new();
+        for index in 0..conversation.messages.len() {
+            match conversation.parent(index) {
+                Some(parent) => children[parent].push(index),
+                None => roots.push(index),
+            }
+        }
+
+        let mut stdout = io::stdout().lock();
+        let mut stack: Vec<_> = roots
+            .into_iter()
+            .rev()
+            .map(|index| (index, String::new(), None))
+            .collect();
+        while let Some((index, prefix, last)) = stack.pop() {
+            let message = &conversation.messages[index];
+            let (connector, indent) = match last {
+                None => ("", ""),
+                Some(false) => ("├─ ", "│  "),
+                Some(true) => ("└─ ", "   "),
+            };
+            writeln!(
+                stdout,
+                "{prefix}{connector}{id} {role}: {content}",
+                id = conversation.id(index),
+                role = message.role,
+                content = snippet(&message.content.to_string())
+            )?;
+
+            let prefix = format!("{prefix}{indent}");
+            let count = children[index].len();
+            for (position, &child) in children[index].iter().enumerate().rev() {
+                stack.push((child, prefix.clone(), Some(position + 1 == count)));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn threads_follow_parents() {
+        let mut conversation = Conversation::default();
+        conversation.push(Message::from_system("You are a date of birth checker."));
+        conversation.push(Message::from_user("Malcolm X"));
+        conversation.push(Message::from_user("Malcolm Little"));
+        assert!(!conversation.is_branched());
+
+        let alternative =
+            conversation.append(Some(1), Message::from_user("Martin Luther King Jr."));
+        assert!(conversation.is_branched());
+        assert_eq!(conversation.messages[1].id.as_deref(), Some("2"));
+        assert_eq!(conversation.find("2"), Some(1));
+
+        let thread = conversation.thread(Some(alternative));
+        let contents: Vec<_> = thread
+            .messages
+            .iter()
+            .map(|message| message.content.to_string())
+            .collect();
+        assert_eq!(
+            contents,
+            [
+                "You are a date of birth checker.",
+                "Malcolm X",
+                "Martin Luther King Jr."
+            ]
+        );
+    }
+}